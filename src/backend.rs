@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use pulse::{channelmap::Map, volume::ChannelVolumes};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceKind {
+    Sink,
+    Source,
+}
+
+impl DeviceKind {
+    /// The kind of streams that play to or record from this kind of device
+    pub fn streams(self) -> StreamKind {
+        match self {
+            DeviceKind::Sink => StreamKind::SinkInput,
+            DeviceKind::Source => StreamKind::SourceOutput,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    /// Playback
+    SinkInput,
+    /// Recording
+    SourceOutput,
+}
+
+/// A sink or source
+#[derive(Clone, Debug)]
+pub struct Device {
+    pub index: u32,
+    pub name: Option<String>,
+    pub volume: ChannelVolumes,
+    /// Which speaker each channel of `volume` is for
+    pub channel_map: Map,
+    pub mute: bool,
+    pub properties: HashMap<String, String>,
+    /// Names of its ports, e.g. "analog-output-headphones"
+    pub ports: Vec<String>,
+    pub active_port: Option<String>,
+}
+
+/// An application's playback or recording stream
+#[derive(Clone, Debug)]
+pub struct Stream {
+    pub index: u32,
+    /// Index of the sink it plays to or the source it records from
+    pub device: u32,
+    pub volume: ChannelVolumes,
+    pub channel_map: Map,
+    pub mute: bool,
+    pub properties: HashMap<String, String>,
+}
+
+/// A sound card, which has profiles that decide which sinks and sources it provides
+#[derive(Clone, Debug)]
+pub struct Card {
+    pub index: u32,
+    pub name: Option<String>,
+    /// Names of every profile, e.g. "a2dp_sink" or "output:analog-stereo"
+    pub profiles: Vec<String>,
+    pub active_profile: Option<String>,
+    pub properties: HashMap<String, String>,
+}
+
+/// Everything bindings need from the sound server
+pub trait AudioBackend {
+    fn devices(&mut self, kind: DeviceKind) -> Result<Vec<Device>>;
+    fn default_device(&mut self, kind: DeviceKind) -> Result<Option<Device>>;
+    fn set_default_device(&mut self, kind: DeviceKind, name: &str) -> Result<()>;
+    fn set_device_volume(
+        &mut self,
+        kind: DeviceKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()>;
+    fn set_device_mute(&mut self, kind: DeviceKind, index: u32, mute: bool) -> Result<()>;
+    fn set_device_port(&mut self, kind: DeviceKind, index: u32, port: &str) -> Result<()>;
+
+    fn streams(&mut self, kind: StreamKind) -> Result<Vec<Stream>>;
+    fn set_stream_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()>;
+    fn set_stream_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<()>;
+    /// Moves a stream to another sink or source
+    fn move_stream(&mut self, kind: StreamKind, index: u32, device: u32) -> Result<()>;
+
+    fn cards(&mut self) -> Result<Vec<Card>>;
+    fn set_card_profile(&mut self, index: u32, profile: &str) -> Result<()>;
+
+    /// `false` once the server has gone away
+    fn is_connected(&self) -> bool;
+    fn reconnect(&mut self) -> Result<()>;
+}
+
+/// Remembers what the server reported for as long as it lives, so refreshing many bindings at
+/// once lists each kind of device and stream a single time. Changes are passed straight through
+/// and make it forget everything, as they can affect more than what they touch.
+pub struct Snapshot<'a> {
+    backend: &'a mut dyn AudioBackend,
+    devices: HashMap<DeviceKind, Vec<Device>>,
+    defaults: HashMap<DeviceKind, Option<Device>>,
+    streams: HashMap<StreamKind, Vec<Stream>>,
+    cards: Option<Vec<Card>>,
+}
+
+impl<'a> Snapshot<'a> {
+    pub fn new(backend: &'a mut dyn AudioBackend) -> Self {
+        Snapshot {
+            backend,
+            devices: HashMap::new(),
+            defaults: HashMap::new(),
+            streams: HashMap::new(),
+            cards: None,
+        }
+    }
+
+    fn forget(&mut self) {
+        self.devices.clear();
+        self.defaults.clear();
+        self.streams.clear();
+        self.cards = None;
+    }
+}
+
+impl AudioBackend for Snapshot<'_> {
+    fn devices(&mut self, kind: DeviceKind) -> Result<Vec<Device>> {
+        if let Some(devices) = self.devices.get(&kind) {
+            return Ok(devices.clone());
+        }
+        let devices = self.backend.devices(kind)?;
+        self.devices.insert(kind, devices.clone());
+        Ok(devices)
+    }
+
+    fn default_device(&mut self, kind: DeviceKind) -> Result<Option<Device>> {
+        if let Some(default) = self.defaults.get(&kind) {
+            return Ok(default.clone());
+        }
+        let default = self.backend.default_device(kind)?;
+        self.defaults.insert(kind, default.clone());
+        Ok(default)
+    }
+
+    fn set_default_device(&mut self, kind: DeviceKind, name: &str) -> Result<()> {
+        self.forget();
+        self.backend.set_default_device(kind, name)
+    }
+
+    fn set_device_volume(
+        &mut self,
+        kind: DeviceKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        self.forget();
+        self.backend.set_device_volume(kind, index, volume)
+    }
+
+    fn set_device_mute(&mut self, kind: DeviceKind, index: u32, mute: bool) -> Result<()> {
+        self.forget();
+        self.backend.set_device_mute(kind, index, mute)
+    }
+
+    fn set_device_port(&mut self, kind: DeviceKind, index: u32, port: &str) -> Result<()> {
+        self.forget();
+        self.backend.set_device_port(kind, index, port)
+    }
+
+    fn streams(&mut self, kind: StreamKind) -> Result<Vec<Stream>> {
+        if let Some(streams) = self.streams.get(&kind) {
+            return Ok(streams.clone());
+        }
+        let streams = self.backend.streams(kind)?;
+        self.streams.insert(kind, streams.clone());
+        Ok(streams)
+    }
+
+    fn set_stream_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        self.forget();
+        self.backend.set_stream_volume(kind, index, volume)
+    }
+
+    fn set_stream_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<()> {
+        self.forget();
+        self.backend.set_stream_mute(kind, index, mute)
+    }
+
+    fn move_stream(&mut self, kind: StreamKind, index: u32, device: u32) -> Result<()> {
+        self.forget();
+        self.backend.move_stream(kind, index, device)
+    }
+
+    fn cards(&mut self) -> Result<Vec<Card>> {
+        if let Some(cards) = &self.cards {
+            return Ok(cards.clone());
+        }
+        let cards = self.backend.cards()?;
+        self.cards = Some(cards.clone());
+        Ok(cards)
+    }
+
+    fn set_card_profile(&mut self, index: u32, profile: &str) -> Result<()> {
+        self.forget();
+        self.backend.set_card_profile(index, profile)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.backend.is_connected()
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.forget();
+        self.backend.reconnect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake_backend::{device, stream, FakeBackend};
+
+    #[test]
+    fn snapshot_lists_once_until_something_changes() {
+        let mut backend = FakeBackend {
+            sinks: vec![device(1, "speakers")],
+            sink_inputs: vec![stream(10, 1, &[("application.name", "mpv")])],
+            ..FakeBackend::default()
+        };
+        let mut snapshot = Snapshot::new(&mut backend);
+        for _ in 0..3 {
+            snapshot.devices(DeviceKind::Sink).unwrap();
+            snapshot.streams(StreamKind::SinkInput).unwrap();
+        }
+        snapshot.set_device_mute(DeviceKind::Sink, 1, true).unwrap();
+        assert!(snapshot.devices(DeviceKind::Sink).unwrap()[0].mute);
+        assert_eq!(backend.lists, 3);
+    }
+}
@@ -1,38 +1,328 @@
-use crate::target::Target;
+use pulse::volume::Volume;
+use serde::Deserialize;
+
+use crate::{
+    events::PulseEvent,
+    target::{Aggregate, MoveStreams, Target},
+};
+
+/// How close a knob or fader has to come to the volume it controls to pick it up
+const PICKUP_TOLERANCE: f32 = 2.0 / 127.0;
 
 pub enum Binding {
-    VolumeControl(Target),
+    VolumeControl(Target, VolumeOptions),
+    /// Pans between the left and right channels
+    Balance(Target),
     MuteToggle(Target),
-    DefaultSelect(Target),
+    DefaultSelect(Target, MoveStreams),
+    /// Moves streams to a sink or source, or on to the next one in the list on each press
+    Route(Target, Vec<Target>),
+    /// Makes the next sink or source in the list the default on each press. The LED is set to
+    /// the value for the current one, or is on while any of them is the default without values.
+    Cycle(Vec<Target>, MoveStreams, Vec<u8>),
+    /// Switches a card to the profile after its active one in the list, lit while it is on the
+    /// first one. With a single profile this just sets it.
+    CardProfile(Target, Vec<String>),
+    /// Switches a sink or source to the port after its active one in the list, lit while it is
+    /// on the first one
+    Port(Target, Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VolumeOptions {
+    pub pickup: Pickup,
+    /// Set for endless encoders that send how far they were turned instead of a position
+    pub encoder: Option<Encoder>,
+    /// The volume at the top of the control's range, where 1 is 100%
+    pub max_volume: f32,
+    pub curve: Curve,
+    /// How the volume shown is picked when the target is several streams or devices
+    pub aggregate: Aggregate,
+}
+
+impl Default for VolumeOptions {
+    fn default() -> Self {
+        VolumeOptions {
+            pickup: Pickup::default(),
+            encoder: None,
+            max_volume: 1.0,
+            curve: Curve::default(),
+            aggregate: Aggregate::default(),
+        }
+    }
+}
+
+impl VolumeOptions {
+    /// Where a control should be for `volume`, from 0 to 1. The inverse of `volume`, so a
+    /// control's position survives the round trip through pulse.
+    pub fn position(&self, volume: Volume) -> f32 {
+        let fraction = volume.0 as f32 / Volume::NORMAL.0 as f32;
+        let position = match self.curve {
+            Curve::Cubic => fraction / self.max_volume,
+            Curve::Linear => (fraction / self.max_volume).powi(3),
+            Curve::Db(_) if fraction <= 0.0 => 0.0,
+            Curve::Db(floor) => (to_db(fraction) - floor) / (to_db(self.max_volume) - floor),
+        };
+        position.clamp(0.0, 1.0)
+    }
+
+    /// The volume for a control at `position`, from 0 to 1
+    pub fn volume(&self, position: f32) -> Volume {
+        let position = position.clamp(0.0, 1.0);
+        let fraction = match self.curve {
+            Curve::Cubic => position * self.max_volume,
+            Curve::Linear => position.cbrt() * self.max_volume,
+            // The bottom of the range is silent rather than at the floor
+            Curve::Db(_) if position <= 0.0 => 0.0,
+            Curve::Db(floor) => from_db(floor + position * (to_db(self.max_volume) - floor)),
+        };
+        Volume((fraction * Volume::NORMAL.0 as f32).round() as u32)
+    }
+}
+
+/// How a control's position maps to volume. Pulse volumes are already cubic, i.e. 50% is an
+/// eighth of the amplitude of 100%, which is what pavucontrol's sliders show.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    /// Position is proportional to the amplitude
+    Linear,
+    /// Position is proportional to the pulse volume, like pavucontrol
+    #[default]
+    Cubic,
+    /// Position is proportional to the dB above a floor, e.g. -60
+    Db(f32),
+}
+
+/// dB of a pulse volume given as a fraction of 100%
+fn to_db(fraction: f32) -> f32 {
+    60.0 * fraction.log10()
+}
+
+fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 60.0)
+}
+
+/// What happens when a knob or fader is moved while it doesn't match the volume it controls,
+/// e.g. because the volume was changed by another program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pickup {
+    /// The volume jumps to the control's position
+    #[default]
+    Jump,
+    /// Nothing happens until the control is moved to or past the volume
+    Match,
+    /// The volume moves in the same direction, scaled so that it reaches the end of its range
+    /// together with the control
+    Scale,
+}
+
+impl Pickup {
+    /// The new volume, from 0 to 1, for a control moved from `last` to `position` while the
+    /// volume is at `current`. `None` if the volume should be left alone.
+    pub fn apply(self, last: Option<f32>, position: f32, current: f32) -> Option<f32> {
+        let crossed =
+            last.is_some_and(|last| last.min(position) <= current && current <= last.max(position));
+        let matched = crossed || (position - current).abs() <= PICKUP_TOLERANCE;
+        match (self, last) {
+            (Pickup::Jump, _) => Some(position),
+            _ if matched => Some(position),
+            (Pickup::Scale, Some(last)) if position > last => {
+                Some(current + (position - last) * (1.0 - current) / (1.0 - last))
+            }
+            (Pickup::Scale, Some(last)) if position < last => {
+                Some(current - (last - position) * current / last)
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Binding {
-    pub fn volume(t: Target) -> Binding {
-        Self::VolumeControl(t)
+    pub fn volume(t: Target, options: VolumeOptions) -> Binding {
+        Self::VolumeControl(t, options)
+    }
+    pub fn balance(t: Target) -> Binding {
+        Self::Balance(t)
     }
     pub fn mute(t: Target) -> Binding {
         Self::MuteToggle(t)
     }
-    pub fn select(t: Target) -> Binding {
-        Self::DefaultSelect(t)
+    pub fn select(t: Target, move_streams: MoveStreams) -> Binding {
+        Self::DefaultSelect(t, move_streams)
+    }
+    pub fn route(t: Target, devices: Vec<Target>) -> Binding {
+        Self::Route(t, devices)
+    }
+    pub fn cycle(devices: Vec<Target>, move_streams: MoveStreams, leds: Vec<u8>) -> Binding {
+        Self::Cycle(devices, move_streams, leds)
+    }
+    pub fn card_profile(t: Target, profiles: Vec<String>) -> Binding {
+        Self::CardProfile(t, profiles)
     }
+    pub fn port(t: Target, ports: Vec<String>) -> Binding {
+        Self::Port(t, ports)
+    }
+
+    /// Whether a change reported by pulse means this binding's LEDs need to be refreshed
+    pub fn affected_by(&self, event: PulseEvent) -> bool {
+        match self {
+            Binding::VolumeControl(t, _)
+            | Binding::Balance(t)
+            | Binding::MuteToggle(t)
+            | Binding::CardProfile(t, _)
+            | Binding::Port(t, _) => t.affected_by(event),
+            Binding::DefaultSelect(t, _) => {
+                matches!(event, PulseEvent::Server) || t.affected_by(event)
+            }
+            Binding::Route(t, devices) => {
+                t.affected_by(event) || devices.iter().any(|d| d.affected_by(event))
+            }
+            Binding::Cycle(devices, ..) => {
+                matches!(event, PulseEvent::Server) || devices.iter().any(|d| d.affected_by(event))
+            }
+        }
+    }
+}
+
+/// How a relative encoder packs a signed number of ticks into a CC value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// 1 is one tick clockwise, 127 one tick counter-clockwise
+    TwosComplement,
+    /// The low 6 bits are the number of ticks and 64 is set when counter-clockwise
+    SignedBit,
+    /// 65 is one tick clockwise, 63 one tick counter-clockwise
+    BinaryOffset,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Encoder {
+    pub encoding: Encoding,
+    /// How far a single tick moves along the control's range, from 0 to 1
+    pub step: f32,
+    /// Exponent applied to the number of ticks in a message, so that turning quickly covers
+    /// more ground. 1 disables acceleration.
+    pub acceleration: f32,
+}
 
-    pub fn to_mute(&self) -> Self {
-        let t = match self {
-            Binding::VolumeControl(t) => t,
-            Binding::MuteToggle(t) => t,
-            Binding::DefaultSelect(t) => t,
+impl Encoder {
+    /// How far a CC value from the encoder moves along the control's range
+    pub fn delta(&self, value: u8) -> f32 {
+        let ticks = match self.encoding {
+            Encoding::TwosComplement if value >= 64 => value as i32 - 128,
+            Encoding::TwosComplement => value as i32,
+            Encoding::SignedBit if value & 64 != 0 => -((value & 63) as i32),
+            Encoding::SignedBit => value as i32,
+            Encoding::BinaryOffset => value as i32 - 64,
         };
-        Self::MuteToggle(t.clone())
+        (ticks.abs() as f32)
+            .powf(self.acceleration)
+            .copysign(ticks as f32)
+            * self.step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_moves_to(volume: Option<f32>, expected: f32) {
+        let volume = volume.expect("volume should change");
+        assert!(
+            (volume - expected).abs() < 1e-6,
+            "{} != {}",
+            volume,
+            expected
+        );
+    }
+
+    #[test]
+    fn jump_always_follows_the_control() {
+        assert_moves_to(Pickup::Jump.apply(None, 0.8, 0.2), 0.8);
+        assert_moves_to(Pickup::Jump.apply(Some(0.1), 0.3, 0.9), 0.3);
     }
 
-    #[allow(dead_code)]
-    pub fn to_volume(&self) -> Self {
-        let t = match self {
-            Binding::VolumeControl(t) => t,
-            Binding::MuteToggle(t) => t,
-            Binding::DefaultSelect(t) => t,
+    #[test]
+    fn match_waits_for_the_control_to_cross_the_volume() {
+        assert_eq!(Pickup::Match.apply(Some(0.1), 0.3, 0.5), None);
+        assert_eq!(Pickup::Match.apply(Some(0.9), 0.7, 0.5), None);
+        assert_moves_to(Pickup::Match.apply(Some(0.4), 0.6, 0.5), 0.6);
+        assert_moves_to(Pickup::Match.apply(Some(0.6), 0.4, 0.5), 0.4);
+    }
+
+    #[test]
+    fn match_picks_up_within_tolerance() {
+        assert_moves_to(Pickup::Match.apply(None, 0.5, 0.51), 0.5);
+        assert_eq!(Pickup::Match.apply(None, 0.5, 0.6), None);
+    }
+
+    #[test]
+    fn scale_moves_the_volume_towards_the_end_of_its_range() {
+        // 0.4 of the 0.8 left above the control is half, so half of the 0.2 above the volume
+        assert_moves_to(Pickup::Scale.apply(Some(0.2), 0.6, 0.8), 0.9);
+        // 0.3 of the 0.6 below the control is half, so half of the 0.1 below the volume
+        assert_moves_to(Pickup::Scale.apply(Some(0.6), 0.3, 0.1), 0.05);
+        // Crossing the volume picks it up like match
+        assert_moves_to(Pickup::Scale.apply(Some(0.6), 0.2, 0.3), 0.2);
+    }
+
+    #[test]
+    fn scale_from_the_ends_of_the_range() {
+        assert_moves_to(Pickup::Scale.apply(Some(0.0), 0.5, 0.8), 0.9);
+        assert_eq!(Pickup::Scale.apply(Some(0.0), 0.0, 0.8), None);
+        assert_moves_to(Pickup::Scale.apply(Some(1.0), 0.5, 0.2), 0.1);
+        assert_eq!(Pickup::Scale.apply(Some(1.0), 1.0, 0.2), None);
+    }
+
+    #[test]
+    fn scale_needs_a_previous_position() {
+        assert_eq!(Pickup::Scale.apply(None, 0.5, 0.2), None);
+    }
+
+    fn ticks(encoding: Encoding, acceleration: f32, value: u8) -> f32 {
+        let encoder = Encoder {
+            encoding,
+            step: 0.01,
+            acceleration,
         };
-        Self::VolumeControl(t.clone())
+        // In ticks rather than steps to keep the numbers round
+        (encoder.delta(value) / 0.01).round()
+    }
+
+    #[test]
+    fn encoder_decodes_each_encoding() {
+        let cases = [
+            (Encoding::TwosComplement, 1, 1.0),
+            (Encoding::TwosComplement, 3, 3.0),
+            (Encoding::TwosComplement, 127, -1.0),
+            (Encoding::TwosComplement, 125, -3.0),
+            (Encoding::SignedBit, 1, 1.0),
+            (Encoding::SignedBit, 65, -1.0),
+            (Encoding::SignedBit, 67, -3.0),
+            (Encoding::BinaryOffset, 65, 1.0),
+            (Encoding::BinaryOffset, 64, 0.0),
+            (Encoding::BinaryOffset, 63, -1.0),
+            (Encoding::BinaryOffset, 61, -3.0),
+        ];
+        for (encoding, value, expected) in cases {
+            assert_eq!(
+                ticks(encoding, 1.0, value),
+                expected,
+                "{:?} {}",
+                encoding,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn encoder_acceleration_keeps_the_direction() {
+        assert_eq!(ticks(Encoding::TwosComplement, 2.0, 1), 1.0);
+        assert_eq!(ticks(Encoding::TwosComplement, 2.0, 3), 9.0);
+        assert_eq!(ticks(Encoding::TwosComplement, 2.0, 125), -9.0);
+        assert_eq!(ticks(Encoding::SignedBit, 2.0, 66), -4.0);
     }
 }
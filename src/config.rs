@@ -0,0 +1,847 @@
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use toml::{value::Table, Spanned, Value};
+
+use crate::{
+    binding::{Binding, Curve, Encoder, Encoding, Pickup, VolumeOptions},
+    deck::Layer,
+    matcher::Matcher,
+    profile::{Control, MessageKind, Profile, BUILTIN_PROFILES, DEFAULT_PROFILE},
+    target::{Aggregate, MoveStreams, Target},
+};
+
+/// A parsed config file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub midi: MidiConfig,
+    #[serde(default)]
+    profile: ProfileConfig,
+    /// Targets that can be shared between controls with `{ ref = "name" }`
+    #[serde(default)]
+    targets: HashMap<String, Spanned<TargetConfig>>,
+    #[serde(default)]
+    knobs: Controls,
+    #[serde(default)]
+    faders: Controls,
+    #[serde(default)]
+    buttons: Controls,
+    /// Extra layers, e.g. layer A of the X-Touch Mini. The top level profile and bindings are
+    /// the first layer.
+    #[serde(default)]
+    layers: HashMap<String, LayerConfig>,
+
+    /// Source text, kept around to turn spans into line numbers for error messages
+    #[serde(skip)]
+    text: String,
+}
+
+/// Port name patterns, see `midi::PortPattern`. `input` and `output` default to `device`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct MidiConfig {
+    pub device: Option<String>,
+    pub input: Option<String>,
+    pub output: Option<String>,
+}
+
+/// A built in profile, with any of its settings overridden
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ProfileConfig {
+    builtin: Option<String>,
+    /// 1 to 16, as in most controller manuals
+    channel: Option<u8>,
+    knobs: Option<Vec<u8>>,
+    faders: Option<Vec<u8>>,
+    buttons: Option<Vec<u8>>,
+    buttons_send: Option<MessageKind>,
+    knob_leds: Option<bool>,
+    button_leds: Option<bool>,
+    led_on: Option<u8>,
+    led_off: Option<u8>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayerConfig {
+    profile: ProfileConfig,
+    #[serde(default)]
+    knobs: Controls,
+    #[serde(default)]
+    faders: Controls,
+    #[serde(default)]
+    buttons: Controls,
+}
+
+/// Bindings by control number, spanned to point errors at the line they are on
+type Controls = HashMap<String, Spanned<ControlConfig>>;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ControlConfig {
+    volume: Option<TargetConfig>,
+    balance: Option<TargetConfig>,
+    mute: Option<TargetConfig>,
+    select: Option<TargetConfig>,
+    /// Streams to move to the devices in `to`
+    route: Option<TargetConfig>,
+    to: Option<Vec<TargetConfig>>,
+    /// Sinks or sources to make the default in turn
+    cycle: Option<Vec<TargetConfig>>,
+    /// Only for cycle, the LED value for each entry
+    leds: Option<Vec<u8>>,
+    /// A card to switch to the profile after its active one in `profiles`
+    card_profile: Option<TargetConfig>,
+    profiles: Option<Vec<String>>,
+    /// A sink or source to switch to the port after its active one in `ports`
+    port: Option<TargetConfig>,
+    ports: Option<Vec<String>>,
+    /// Only for volume, defaults to "jump" for knobs and "match" for faders
+    pickup: Option<Pickup>,
+    /// Only for volume on knobs
+    encoder: Option<EncoderConfig>,
+    /// Only for volume, in percent. Defaults to 100.
+    max_volume: Option<f32>,
+    /// Only for volume, defaults to "cubic"
+    curve: Option<Curve>,
+    /// Only for volume, defaults to "first"
+    aggregate: Option<Aggregate>,
+    /// Only for select and cycle, `true` to move every stream or a matcher to move some of them
+    move_streams: Option<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EncoderConfig {
+    encoding: Encoding,
+    /// Percent of the knob's range per tick, defaults to 1
+    step: Option<f32>,
+    /// Defaults to 1, i.e. none
+    acceleration: Option<f32>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetConfig {
+    sink: Option<String>,
+    source: Option<String>,
+    sink_by_property: Option<Table>,
+    source_by_property: Option<Table>,
+    sink_input: Option<Table>,
+    source_output: Option<Table>,
+    card: Option<String>,
+    card_by_property: Option<Table>,
+    any: Option<Vec<TargetConfig>>,
+    all: Option<Vec<TargetConfig>>,
+    #[serde(rename = "ref")]
+    reference: Option<Spanned<String>>,
+}
+
+pub fn default_path() -> PathBuf {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default()
+        .join("dreamdeck")
+        .join("config.toml")
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(text)?;
+        config.text = text.to_owned();
+        Ok(config)
+    }
+
+    pub fn layers(&self) -> Result<Vec<Layer>> {
+        let profile = Self::profile(&self.profile)?;
+        let bindings = self.bindings(&profile, &self.knobs, &self.faders, &self.buttons)?;
+        let mut layers = vec![Layer {
+            name: "main".to_owned(),
+            profile,
+            bindings,
+        }];
+
+        let mut names: Vec<&String> = self.layers.keys().collect();
+        names.sort();
+        for name in names {
+            let config = &self.layers[name];
+            let profile = Self::profile(&config.profile)
+                .with_context(|| format!("Invalid profile for layers.{}", name))?;
+            let bindings = self
+                .bindings(&profile, &config.knobs, &config.faders, &config.buttons)
+                .with_context(|| format!("Invalid bindings for layers.{}", name))?;
+            layers.push(Layer {
+                name: name.clone(),
+                profile,
+                bindings,
+            });
+        }
+
+        Ok(layers)
+    }
+
+    fn profile(config: &ProfileConfig) -> Result<Profile> {
+        let name = config.builtin.as_deref().unwrap_or(DEFAULT_PROFILE);
+        let mut profile = Profile::builtin(name).ok_or_else(|| {
+            anyhow!(
+                "Unknown profile {:?}, the built in profiles are: {}",
+                name,
+                BUILTIN_PROFILES.join(", ")
+            )
+        })?;
+
+        if let Some(channel) = config.channel {
+            if !(1..=16).contains(&channel) {
+                bail!("profile.channel must be from 1 to 16");
+            }
+            profile.channel = channel - 1;
+        }
+        for (numbers, field) in [
+            (&config.knobs, &mut profile.knobs),
+            (&config.faders, &mut profile.faders),
+            (&config.buttons, &mut profile.buttons),
+        ] {
+            if let Some(numbers) = numbers {
+                if numbers.iter().any(|n| *n > 127) {
+                    bail!("Control numbers in profile must be from 0 to 127");
+                }
+                *field = numbers.clone();
+            }
+        }
+        profile.buttons_send = config.buttons_send.unwrap_or(profile.buttons_send);
+        profile.knob_leds = config.knob_leds.unwrap_or(profile.knob_leds);
+        profile.button_leds = config.button_leds.unwrap_or(profile.button_leds);
+        profile.led_on = config.led_on.unwrap_or(profile.led_on);
+        profile.led_off = config.led_off.unwrap_or(profile.led_off);
+
+        Ok(profile)
+    }
+
+    fn bindings(
+        &self,
+        profile: &Profile,
+        knobs: &Controls,
+        faders: &Controls,
+        buttons: &Controls,
+    ) -> Result<HashMap<Control, Binding>> {
+        let mut bindings = HashMap::new();
+
+        let sections: [(&str, fn(u8) -> Control, &Controls); 3] = [
+            ("knobs", Control::Knob, knobs),
+            ("faders", Control::Fader, faders),
+            ("buttons", Control::Button, buttons),
+        ];
+        for (section, control_kind, controls) in sections {
+            for (key, control) in controls {
+                let binding = self.binding(section, control.get_ref()).with_context(|| {
+                    format!(
+                        "{}: invalid binding for {}.{}",
+                        self.line(control),
+                        section,
+                        key
+                    )
+                })?;
+                let id = key
+                    .parse::<u8>()
+                    .ok()
+                    .map(control_kind)
+                    .filter(|id| profile.has_control(*id))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "{}: {}.{}: the {} profile has no such control",
+                            self.line(control),
+                            section,
+                            key,
+                            profile.name
+                        )
+                    })?;
+                if bindings.insert(id, binding).is_some() {
+                    bail!(
+                        "{}: {}.{}: control is bound more than once",
+                        self.line(control),
+                        section,
+                        key
+                    );
+                }
+            }
+        }
+
+        Ok(bindings)
+    }
+
+    fn binding(&self, section: &str, control: &ControlConfig) -> Result<Binding> {
+        let target = |config: &TargetConfig| -> Result<Target> {
+            let target = self.target(config, &mut Vec::new())?;
+            if target.is_card() {
+                bail!("card targets can only be used for card_profile bindings");
+            }
+            Ok(target)
+        };
+        let actions = [
+            ("volume", &control.volume),
+            ("balance", &control.balance),
+            ("mute", &control.mute),
+            ("select", &control.select),
+            ("route", &control.route),
+            ("card_profile", &control.card_profile),
+            ("port", &control.port),
+        ];
+        let mut set: Vec<_> = actions
+            .iter()
+            .filter_map(|(action, t)| Some((*action, t.as_ref()?)))
+            .collect();
+        // cycle is the only action with a list of targets
+        let count = set.len() + usize::from(control.cycle.is_some());
+
+        let binding = match (section, set.pop(), &control.cycle) {
+            _ if count != 1 => None,
+            ("knobs" | "faders", Some(("volume", t)), _) => Some(Binding::volume(
+                target(t)?,
+                Self::volume_options(section, control)?,
+            )),
+            ("knobs" | "faders", Some(("balance", t)), _) => Some(Binding::balance(target(t)?)),
+            ("buttons", Some(("mute", t)), _) => Some(Binding::mute(target(t)?)),
+            ("buttons", Some(("select", t)), _) => Some(Self::select(target(t)?, control)?),
+            ("buttons", Some(("route", t)), _) => Some(self.route(target(t)?, &control.to)?),
+            ("buttons", None, Some(targets)) => Some(self.cycle(targets, control)?),
+            ("buttons", Some(("card_profile", t)), _) => {
+                let card = self.target(t, &mut Vec::new())?;
+                Some(Self::card_profile(card, &control.profiles)?)
+            }
+            ("buttons", Some(("port", t)), _) => Some(Self::port(target(t)?, &control.ports)?),
+            _ => None,
+        };
+        let binding = match (binding, section) {
+            (Some(binding), _) => binding,
+            (None, "knobs") => bail!("Knobs must have exactly one of: volume, balance"),
+            (None, "faders") => bail!("Faders must have exactly one of: volume, balance"),
+            (None, _) => {
+                bail!(
+                    "Buttons must have exactly one of: mute, select, route, cycle, card_profile, \
+                    port"
+                )
+            }
+        };
+        if control.to.is_some() && !matches!(binding, Binding::Route(..)) {
+            bail!("to can only be set for route bindings");
+        }
+        if control.profiles.is_some() && !matches!(binding, Binding::CardProfile(..)) {
+            bail!("profiles can only be set for card_profile bindings");
+        }
+        if control.ports.is_some() && !matches!(binding, Binding::Port(..)) {
+            bail!("ports can only be set for port bindings");
+        }
+        let is_volume = matches!(binding, Binding::VolumeControl(..));
+        let volume_only = [
+            control.pickup.is_some(),
+            control.max_volume.is_some(),
+            control.curve.is_some(),
+            control.aggregate.is_some(),
+        ];
+        if volume_only.contains(&true) && !is_volume {
+            bail!("pickup, max_volume, curve and aggregate can only be set for volume bindings");
+        }
+        let is_select = matches!(binding, Binding::DefaultSelect(..) | Binding::Cycle(..));
+        if control.move_streams.is_some() && !is_select {
+            bail!("move_streams can only be set for select and cycle bindings");
+        }
+        if control.leds.is_some() && !matches!(binding, Binding::Cycle(..)) {
+            bail!("leds can only be set for cycle bindings");
+        }
+        if control.encoder.is_some() && !(is_volume && section == "knobs") {
+            bail!("encoder can only be set for volume bindings on knobs");
+        }
+        Ok(binding)
+    }
+
+    fn select(device: Target, control: &ControlConfig) -> Result<Binding> {
+        if device.device_kind().is_none() {
+            bail!("select must be a sink or source target");
+        }
+        Ok(Binding::select(device, Self::move_streams(control)?))
+    }
+
+    fn route(&self, streams: Target, to: &Option<Vec<TargetConfig>>) -> Result<Binding> {
+        let devices = match to {
+            Some(devices) if !devices.is_empty() => self.targets(devices, &mut Vec::new())?,
+            _ => bail!("route needs a list of sinks or sources to move the streams to"),
+        };
+        let kind = streams
+            .stream_kind()
+            .ok_or_else(|| anyhow!("route must be a sink_input or source_output target"))?;
+        if devices
+            .iter()
+            .any(|d| d.device_kind().map(|k| k.streams()) != Some(kind))
+        {
+            bail!("Sink inputs can only be routed to sinks, and source outputs to sources");
+        }
+        Ok(Binding::route(streams, devices))
+    }
+
+    fn cycle(&self, targets: &[TargetConfig], control: &ControlConfig) -> Result<Binding> {
+        let devices = self.targets(targets, &mut Vec::new())?;
+        let kind = devices.first().and_then(Target::device_kind);
+        if kind.is_none() || devices.iter().any(|d| d.device_kind() != kind) {
+            bail!("cycle must be a list of only sinks or only sources");
+        }
+        let leds = control.leds.clone().unwrap_or_default();
+        if !leds.is_empty() && leds.len() != devices.len() {
+            bail!("leds must have a value for each entry in cycle");
+        }
+        if leds.iter().any(|led| *led > 127) {
+            bail!("leds must be from 0 to 127");
+        }
+        Ok(Binding::cycle(devices, Self::move_streams(control)?, leds))
+    }
+
+    fn card_profile(card: Target, profiles: &Option<Vec<String>>) -> Result<Binding> {
+        if !card.is_card() {
+            bail!("card_profile must be a card or card_by_property target");
+        }
+        match profiles {
+            Some(profiles) if !profiles.is_empty() => {
+                Ok(Binding::card_profile(card, profiles.clone()))
+            }
+            _ => bail!("card_profile needs a list of profiles to switch between"),
+        }
+    }
+
+    fn port(device: Target, ports: &Option<Vec<String>>) -> Result<Binding> {
+        if device.device_kind().is_none() {
+            bail!("port must be a sink or source target");
+        }
+        match ports {
+            Some(ports) if !ports.is_empty() => Ok(Binding::port(device, ports.clone())),
+            _ => bail!("port needs a list of ports to switch between"),
+        }
+    }
+
+    fn move_streams(control: &ControlConfig) -> Result<MoveStreams> {
+        Ok(match &control.move_streams {
+            None | Some(Value::Boolean(false)) => MoveStreams::None,
+            Some(Value::Boolean(true)) => MoveStreams::All,
+            Some(Value::Table(properties)) => {
+                MoveStreams::Matching(matcher(properties).context("Invalid move_streams")?)
+            }
+            Some(_) => bail!("move_streams must be true, false or a matcher"),
+        })
+    }
+
+    fn volume_options(section: &str, control: &ControlConfig) -> Result<VolumeOptions> {
+        // Knob rings show the volume, faders can't be moved to match it
+        let default_pickup = match section {
+            "faders" => Pickup::Match,
+            _ => Pickup::Jump,
+        };
+        let encoder = match &control.encoder {
+            Some(_) if control.pickup.is_some() => {
+                bail!("pickup doesn't apply to encoders, they always move from the current volume")
+            }
+            Some(config) => {
+                let step = config.step.unwrap_or(1.0);
+                let acceleration = config.acceleration.unwrap_or(1.0);
+                if step <= 0.0 || acceleration < 1.0 {
+                    bail!("encoder step must be positive and acceleration at least 1");
+                }
+                Some(Encoder {
+                    encoding: config.encoding,
+                    step: step / 100.0,
+                    acceleration,
+                })
+            }
+            None => None,
+        };
+        let max_volume = control.max_volume.unwrap_or(100.0);
+        if max_volume <= 0.0 {
+            bail!("max_volume must be positive");
+        }
+        let curve = control.curve.unwrap_or_default();
+        if let Curve::Db(floor) = curve {
+            // The top of the range is 0dB at 100%
+            if floor >= 60.0 * (max_volume / 100.0).log10() {
+                bail!("The floor of a db curve must be below the dB of max_volume");
+            }
+        }
+        Ok(VolumeOptions {
+            pickup: control.pickup.unwrap_or(default_pickup),
+            encoder,
+            max_volume: max_volume / 100.0,
+            curve,
+            aggregate: control.aggregate.unwrap_or_default(),
+        })
+    }
+
+    fn target(&self, config: &TargetConfig, visiting: &mut Vec<String>) -> Result<Target> {
+        let kinds = [
+            config.sink.is_some(),
+            config.source.is_some(),
+            config.sink_by_property.is_some(),
+            config.source_by_property.is_some(),
+            config.sink_input.is_some(),
+            config.source_output.is_some(),
+            config.card.is_some(),
+            config.card_by_property.is_some(),
+            config.any.is_some(),
+            config.all.is_some(),
+            config.reference.is_some(),
+        ];
+        if kinds.iter().filter(|&&k| k).count() != 1 {
+            bail!(
+                "Targets must have exactly one of: sink, source, sink_by_property, \
+                source_by_property, sink_input, source_output, card, card_by_property, any, all, ref"
+            );
+        }
+
+        if let Some(name) = &config.sink {
+            Ok(Target::SinkByName(name.clone()))
+        } else if let Some(name) = &config.source {
+            Ok(Target::SourceByName(name.clone()))
+        } else if let Some(properties) = &config.sink_by_property {
+            Ok(Target::SinkByProperty(
+                matcher(properties).context("Invalid sink_by_property")?,
+            ))
+        } else if let Some(properties) = &config.source_by_property {
+            Ok(Target::SourceByProperty(
+                matcher(properties).context("Invalid source_by_property")?,
+            ))
+        } else if let Some(properties) = &config.sink_input {
+            Ok(Target::SinkWithProperty(
+                matcher(properties).context("Invalid sink_input")?,
+            ))
+        } else if let Some(properties) = &config.source_output {
+            Ok(Target::SourceOutputWithProperty(
+                matcher(properties).context("Invalid source_output")?,
+            ))
+        } else if let Some(name) = &config.card {
+            Ok(Target::CardByName(name.clone()))
+        } else if let Some(properties) = &config.card_by_property {
+            Ok(Target::CardByProperty(
+                matcher(properties).context("Invalid card_by_property")?,
+            ))
+        } else if let Some(targets) = &config.any {
+            if targets.is_empty() {
+                bail!("any must list at least one target");
+            }
+            Ok(Target::Any(self.group(targets, visiting)?))
+        } else if let Some(targets) = &config.all {
+            if targets.is_empty() {
+                bail!("all must list at least one target");
+            }
+            Ok(Target::All(self.group(targets, visiting)?))
+        } else if let Some(name) = &config.reference {
+            let target = self.targets.get(name.get_ref()).ok_or_else(|| {
+                anyhow!("{}: no target named {:?}", self.line(name), name.get_ref())
+            })?;
+            if visiting.contains(name.get_ref()) {
+                bail!(
+                    "{}: target {:?} refers to itself",
+                    self.line(name),
+                    name.get_ref()
+                );
+            }
+            visiting.push(name.get_ref().clone());
+            let resolved = self.target(target.get_ref(), visiting).with_context(|| {
+                format!("{}: invalid target {:?}", self.line(target), name.get_ref())
+            });
+            visiting.pop();
+            resolved
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn targets(&self, configs: &[TargetConfig], visiting: &mut Vec<String>) -> Result<Vec<Target>> {
+        configs.iter().map(|t| self.target(t, visiting)).collect()
+    }
+
+    /// The targets of `any` or `all`, which only work for sinks, sources and streams
+    fn group(&self, configs: &[TargetConfig], visiting: &mut Vec<String>) -> Result<Vec<Target>> {
+        let targets = self.targets(configs, visiting)?;
+        if targets.iter().any(Target::is_card) {
+            bail!("Cards can't be used in any or all");
+        }
+        Ok(targets)
+    }
+
+    fn line<T>(&self, value: &Spanned<T>) -> String {
+        format!(
+            "line {}",
+            self.text[..value.start()].matches('\n').count() + 1
+        )
+    }
+}
+
+/// All of `properties` have to match. A value is either the exact text, or a table with one
+/// of `equals`, `glob` or `regex` and optionally `ignore_case`. The keys `not`, `any_of` and
+/// `all_of` nest other matchers.
+fn matcher(properties: &Table) -> Result<Matcher> {
+    if properties.is_empty() {
+        bail!("Must match at least one property");
+    }
+    let mut matchers = Vec::new();
+    for (key, value) in properties {
+        let matcher = match (key.as_str(), value) {
+            ("not", Value::Table(table)) => Matcher::Not(Box::new(matcher(table)?)),
+            ("any_of" | "all_of", Value::Array(values)) if values.is_empty() => {
+                bail!("{} must list at least one matcher", key)
+            }
+            ("any_of" | "all_of", Value::Array(values)) => {
+                let nested: Vec<Matcher> = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Table(table) => matcher(table),
+                        _ => Err(anyhow!("{} must be a list of tables", key)),
+                    })
+                    .collect::<Result<_>>()?;
+                match key.as_str() {
+                    "any_of" => Matcher::Any(nested),
+                    _ => Matcher::All(nested),
+                }
+            }
+            ("not", _) => bail!("not must be a table"),
+            ("any_of" | "all_of", _) => bail!("{} must be a list of tables", key),
+            (property, Value::String(value)) => Matcher::equals(property, value),
+            (property, Value::Table(table)) => pattern(property, table)?,
+            (property, _) => bail!("{} must be a string or a table", property),
+        };
+        matchers.push(matcher);
+    }
+    Ok(if matchers.len() == 1 {
+        matchers.remove(0)
+    } else {
+        Matcher::All(matchers)
+    })
+}
+
+fn pattern(property: &str, table: &Table) -> Result<Matcher> {
+    let ignore_case = match table.get("ignore_case") {
+        None => false,
+        Some(Value::Boolean(ignore_case)) => *ignore_case,
+        Some(_) => bail!("{}: ignore_case must be true or false", property),
+    };
+    let mut kinds = table
+        .iter()
+        .filter(|(key, _)| key.as_str() != "ignore_case");
+    match (kinds.next(), kinds.next()) {
+        (Some((kind, Value::String(pattern))), None) => match (kind.as_str(), ignore_case) {
+            ("equals", false) => Ok(Matcher::equals(property, pattern)),
+            ("equals", true) => Matcher::equals_ignore_case(property, pattern),
+            ("glob", _) => Matcher::glob(property, pattern, ignore_case),
+            ("regex", _) => Matcher::regex(property, pattern, ignore_case),
+            _ => bail!("{}: unknown match kind {:?}", property, kind),
+        },
+        _ => bail!(
+            "{}: must have exactly one of: equals, glob, regex, set to a string",
+            property
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The error from loading `text`, with all of its context
+    fn error(text: &str) -> String {
+        match Config::parse(text).and_then(|config| config.layers()) {
+            Ok(_) => panic!("config should be invalid:\n{}", text),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    fn assert_error(text: &str, expected: &str) {
+        let error = error(text);
+        assert!(
+            error.contains(expected),
+            "{:?} doesn't contain {:?}",
+            error,
+            expected
+        );
+    }
+
+    #[test]
+    fn example_config_is_valid() {
+        let config = Config::parse(include_str!("../config.example.toml")).unwrap();
+        assert!(config.layers().is_ok());
+    }
+
+    #[test]
+    fn binding_errors_point_at_their_line() {
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { sink = 'a' } }\n\
+             33 = { mute = { sink = 'a' }, select = { sink = 'a' } }\n",
+            "line 3: invalid binding for buttons.33: Buttons must have exactly one of",
+        );
+        assert_error(
+            "[knobs]\n\
+             11 = { mute = { sink = 'a' } }\n",
+            "line 2: invalid binding for knobs.11: Knobs must have exactly one of: volume, balance",
+        );
+        assert_error(
+            "[faders]\n\
+             10 = {}\n",
+            "line 2: invalid binding for faders.10: Faders must have exactly one of",
+        );
+    }
+
+    #[test]
+    fn controls_must_exist_in_the_profile() {
+        assert_error(
+            "[buttons]\n\
+             99 = { mute = { sink = 'a' } }\n",
+            "line 2: buttons.99: the x-touch-mini-b profile has no such control",
+        );
+    }
+
+    #[test]
+    fn targets_must_have_exactly_one_kind() {
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { sink = 'a', source = 'b' } }\n",
+            "line 2: invalid binding for buttons.32: Targets must have exactly one of",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { any = [] } }\n",
+            "any must list at least one target",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { all = [] } }\n",
+            "all must list at least one target",
+        );
+    }
+
+    #[test]
+    fn matchers_must_match_something() {
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { sink_input = {} } }\n",
+            "Invalid sink_input: Must match at least one property",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { sink_input = { any_of = [] } } }\n",
+            "Invalid sink_input: any_of must list at least one matcher",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { sink_by_property = { not = { all_of = [] } } } }\n",
+            "Invalid sink_by_property: all_of must list at least one matcher",
+        );
+    }
+
+    #[test]
+    fn refs_must_exist() {
+        assert_error(
+            "[targets]\n\
+             a = { sink = 'a' }\n\
+             [buttons]\n\
+             32 = { mute = { ref = 'b' } }\n",
+            "line 4: no target named \"b\"",
+        );
+    }
+
+    #[test]
+    fn refs_must_not_loop() {
+        assert_error(
+            "[targets]\n\
+             a = { ref = 'b' }\n\
+             b = { ref = 'a' }\n\
+             [buttons]\n\
+             32 = { mute = { ref = 'a' } }\n",
+            "line 3: target \"a\" refers to itself",
+        );
+    }
+
+    #[test]
+    fn errors_in_named_targets_point_at_them() {
+        assert_error(
+            "[targets]\n\
+             a = { sink = 'a' }\n\
+             b = { sink_input = {} }\n\
+             [buttons]\n\
+             32 = { mute = { ref = 'b' } }\n",
+            "line 3: invalid target \"b\": Invalid sink_input: Must match at least one property",
+        );
+    }
+
+    #[test]
+    fn targets_must_suit_the_binding() {
+        assert_error(
+            "[buttons]\n\
+             32 = { select = { sink_input = { 'application.name' = 'mpv' } } }\n",
+            "select must be a sink or source target",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { card = 'headset' } }\n",
+            "card targets can only be used for card_profile bindings",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { mute = { any = [{ sink = 'a' }, { card = 'headset' }] } }\n",
+            "Cards can't be used in any or all",
+        );
+        assert_error(
+            "[buttons]\n\
+             32 = { card_profile = { sink = 'a' }, profiles = ['a2dp_sink'] }\n",
+            "card_profile must be a card or card_by_property target",
+        );
+    }
+
+    #[test]
+    fn options_only_apply_to_their_binding() {
+        let cases = [
+            (
+                "to = [{ sink = 'a' }]",
+                "to can only be set for route bindings",
+            ),
+            (
+                "profiles = ['a2dp_sink']",
+                "profiles can only be set for card_profile",
+            ),
+            (
+                "ports = ['analog-output']",
+                "ports can only be set for port bindings",
+            ),
+            ("leds = [1, 2]", "leds can only be set for cycle bindings"),
+            ("pickup = 'match'", "can only be set for volume bindings"),
+            ("max_volume = 150.0", "can only be set for volume bindings"),
+            (
+                "move_streams = true",
+                "move_streams can only be set for select and cycle",
+            ),
+        ];
+        for (option, expected) in cases {
+            assert_error(
+                &format!(
+                    "[buttons]\n32 = {{ mute = {{ sink = 'a' }}, {} }}\n",
+                    option
+                ),
+                expected,
+            );
+        }
+        assert_error(
+            "[faders]\n\
+             10 = { volume = { sink = 'a' }, encoder = { encoding = 'twos_complement' } }\n",
+            "encoder can only be set for volume bindings on knobs",
+        );
+    }
+}
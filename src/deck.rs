@@ -1,117 +1,350 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
-use midir::MidiOutputConnection;
-use pulse::volume::Volume;
-use pulsectl::controllers::{SinkController, SourceController};
 
-use crate::{binding::Binding, binding::Binding::*};
+use crate::{
+    backend::{AudioBackend, Snapshot},
+    binding::Binding::*,
+    binding::{Binding, Pickup},
+    config::Config,
+    events::{PulseEvent, MAX_BACKOFF, MIN_BACKOFF},
+    midi::MidiSink,
+    profile::{Control, Input, Profile},
+    target::Target,
+};
 
-const KNOB_UPDATE: u8 = 0xBA;
-const BTN_DOWN: u8 = 0x9A;
-const BTN_UP: u8 = 0x8A;
+/// A set of bindings for the controls of one profile. Controllers like the X-Touch Mini have
+/// several layers that send different messages from the same physical controls.
+pub struct Layer {
+    pub name: String,
+    pub profile: Profile,
+    pub bindings: HashMap<Control, Binding>,
+}
 
 pub struct Deck {
-    sink: SinkController,
-    source: SourceController,
+    backend: Box<dyn AudioBackend>,
+
+    /// Never empty
+    layers: Vec<Layer>,
+    /// Index into `layers` of the one the board was last used on
+    active: usize,
+    /// Last position each knob and fader was seen at or moved to, for picking up volumes
+    positions: HashMap<Control, u8>,
 
-    bindings: HashMap<u8, Binding>,
+    /// `false` from when the pulse server goes away until the backend has reconnected
+    connected: bool,
+    backoff: Duration,
+    retry_at: Instant,
 
-    midi_out: MidiOutputConnection,
+    /// `None` while the board is unplugged
+    midi_out: Option<Box<dyn MidiSink>>,
 }
 
 impl Deck {
-    pub fn new(
-        sink: SinkController,
-        source: SourceController,
-        midi_out: MidiOutputConnection,
-        bindings: HashMap<u8, Binding>,
-    ) -> Self {
+    pub fn new(backend: Box<dyn AudioBackend>) -> Self {
         Deck {
-            sink,
-            source,
-            bindings,
-            midi_out,
+            backend,
+            layers: vec![Layer {
+                name: "main".to_owned(),
+                profile: Profile::default(),
+                bindings: HashMap::new(),
+            }],
+            active: 0,
+            positions: HashMap::new(),
+            connected: true,
+            backoff: MIN_BACKOFF,
+            retry_at: Instant::now(),
+            midi_out: None,
         }
     }
 
-    pub fn clear(&mut self) -> Result<()> {
-        for i in 11..=18 {
-            self.midi_out.send(&[KNOB_UPDATE, i, 0])?;
+    /// Called when the board is plugged in or unplugged. A newly connected board is redrawn.
+    pub fn set_midi_out(&mut self, midi_out: Option<Box<dyn MidiSink>>) -> Result<()> {
+        self.midi_out = midi_out;
+        self.clear()?;
+        self.flush_values_to_board()
+    }
+
+    /// Replaces the current layers with the ones from `config` and redraws the board.
+    /// If any of the new bindings fail to resolve the current ones are kept. Once they are in
+    /// use, failing to redraw is only logged.
+    pub fn load_config(&mut self, config: &Config) -> Result<()> {
+        let layers = config.layers()?;
+        // Blank the LEDs of the old profile, in case the new one uses other controls
+        let cleared = self.clear();
+        self.layers = layers;
+        self.active = 0;
+        let result = cleared
+            .and_then(|_| self.clear())
+            .and_then(|_| self.flush_values_to_board());
+        if let Err(e) = self.check_connection(result) {
+            eprintln!("Failed to redraw the board: {:#}", e);
+        }
+        Ok(())
+    }
+
+    /// Retries connecting to pulse if the connection was lost, backing off exponentially
+    pub fn tick(&mut self) -> Result<()> {
+        if self.connected || Instant::now() < self.retry_at {
+            return Ok(());
+        }
+        match self.reconnect() {
+            Ok(()) => {
+                self.backoff = MIN_BACKOFF;
+                Ok(())
+            }
+            Err(e) => {
+                let delay = self.backoff;
+                self.retry_at = Instant::now() + delay;
+                self.backoff = (delay * 2).min(MAX_BACKOFF);
+                Err(e.context(format!(
+                    "Failed to reconnect to pulse, retrying in {:?}",
+                    delay
+                )))
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        self.backend.reconnect()?;
+        self.connected = true;
+        println!("Reconnected deck to pulse");
+        self.clear()?;
+        self.flush_values_to_board()
+    }
+
+    /// We aren't always told when the server goes away, so after anything fails check whether
+    /// the connection is still up
+    fn check_connection(&mut self, result: Result<()>) -> Result<()> {
+        if result.is_err() && !self.backend.is_connected() {
+            self.connected = false;
+            self.retry_at = Instant::now();
         }
-        for i in 24..=47 {
-            self.midi_out.send(&[BTN_DOWN, i, 0])?;
+        result
+    }
+
+    /// Blanks the LEDs of every layer
+    pub fn clear(&mut self) -> Result<()> {
+        // Whatever was known about the positions is stale once the board changes
+        self.positions.clear();
+        for layer in &self.layers {
+            let profile = &layer.profile;
+            for &knob in &profile.knobs {
+                send_knob(&mut self.midi_out, &mut self.positions, profile, knob, 0)?;
+            }
+            for &button in &profile.buttons {
+                send(&mut self.midi_out, profile.button_led(button, false))?;
+            }
         }
         Ok(())
     }
 
+    /// Called when a message arrives from another layer than the active one. Every layer is
+    /// kept up to date, but the new one is redrawn in case it shares controls with another.
+    fn switch_layer(&mut self, layer: usize) -> Result<()> {
+        self.active = layer;
+        println!("Switched to layer {}", self.layers[layer].name);
+        self.flush_values_to_board()
+    }
+
     pub fn flush_values_to_board(&mut self) -> Result<()> {
-        for (&control, binding) in self.bindings.iter() {
-            match binding {
-                Binding::VolumeControl(target) => {
-                    if let Some(vol) = target.volume(&mut self.sink, &mut self.source)? {
-                        let val: u8 = ((vol.0 * 127) / Volume::NORMAL.0) as u8;
-                        self.midi_out
-                            .send(&[KNOB_UPDATE, control, val.clamp(0, 127)])?;
-                    } else {
-                        self.midi_out.send(&[KNOB_UPDATE, control, 0])?;
-                    }
-                }
-                Binding::MuteToggle(target) => {
-                    if let Some(is_muted) = target.muted(&mut self.sink, &mut self.source)? {
-                        self.midi_out.send(&[BTN_DOWN, control, is_muted.into()])?;
-                    } else {
-                        self.midi_out.send(&[BTN_DOWN, control, 0])?;
-                    }
-                }
-                Binding::DefaultSelect(target) => {
-                    let is_selected = target
-                        .selected(&mut self.sink, &mut self.source)?
-                        .unwrap_or_default();
-                    self.midi_out
-                        .send(&[BTN_DOWN, control, is_selected.into()])?;
+        if self.midi_out.is_none() || !self.connected {
+            return Ok(());
+        }
+        self.flush_layers(|_| true)
+    }
+
+    /// Refreshes the bound controls of every layer that pass `filter`. The board remembers the
+    /// LEDs of the layers it isn't showing, so they are already right when it switches. The
+    /// active layer goes last, so it wins where layers share control numbers. Devices and
+    /// streams are only listed once for all of them.
+    fn flush_layers(&mut self, filter: impl Fn(&Binding) -> bool) -> Result<()> {
+        let (active, layers, filter) = (self.active, &self.layers, &filter);
+        let controls: Vec<(usize, Control)> = (0..layers.len())
+            .filter(|&i| i != active)
+            .chain(std::iter::once(active))
+            .flat_map(move |i| {
+                layers[i]
+                    .bindings
+                    .iter()
+                    .filter(move |(_, binding)| filter(binding))
+                    .map(move |(&control, _)| (i, control))
+            })
+            .collect();
+        let mut backend = Snapshot::new(self.backend.as_mut());
+        for (layer, control) in controls {
+            flush_control(
+                &mut backend,
+                &mut self.midi_out,
+                &mut self.positions,
+                &self.layers[layer],
+                control,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Only refreshes the controls whose state could have been changed by one of `events`.
+    /// Pulse sends them in bursts, e.g. one per stream when a device goes away, so everything
+    /// queued up is handled in one go.
+    pub fn handle_pulse_events(&mut self, events: &[PulseEvent]) -> Result<()> {
+        for event in events {
+            match event {
+                PulseEvent::Disconnected => self.connected = false,
+                PulseEvent::Connected => {
+                    // Don't wait out the backoff, the server is known to be back
+                    self.retry_at = Instant::now();
+                    self.tick()?;
                 }
+                _ => {}
             }
         }
+        if self.midi_out.is_none() || !self.connected {
+            return Ok(());
+        }
 
-        Ok(())
+        let result = self.flush_layers(|binding| events.iter().any(|&e| binding.affected_by(e)));
+        self.check_connection(result)
     }
 
     pub fn knob_update(&mut self, knob: u8, value: u8) -> Result<()> {
-        if let Some(binding) = self.bindings.get(&knob) {
-            if let VolumeControl(target) = binding {
-                target.set_volume(&mut self.sink, &mut self.source, value as f32 / 127.0)?;
-            } else {
-                return Err(anyhow!("Only knobs can be bound to volume control"));
-            }
+        let layer = &self.layers[self.active];
+        if layer.bindings.contains_key(&Control::Knob(knob)) {
+            self.move_control(Control::Knob(knob), value)
         } else {
             // Ignore and zero out changes to unmapped knobs
-            self.midi_out.send(&[KNOB_UPDATE, knob, 0])?
+            send_knob(
+                &mut self.midi_out,
+                &mut self.positions,
+                &layer.profile,
+                knob,
+                0,
+            )
+        }
+    }
+
+    pub fn fader_update(&mut self, fader: u8, value: u8) -> Result<()> {
+        self.move_control(Control::Fader(fader), value)
+    }
+
+    /// Sets the volume bound to a knob or fader, unless its pickup mode says otherwise. Values
+    /// from encoders are instead added to the current volume.
+    fn move_control(&mut self, control: Control, value: u8) -> Result<()> {
+        let last = self.positions.insert(control, value);
+        let (target, options) = match self.layers[self.active].bindings.get(&control) {
+            Some(VolumeControl(target, options)) => (target, options),
+            Some(Balance(target)) => {
+                target.set_balance(self.backend.as_mut(), value_to_balance(value))?;
+                return Ok(());
+            }
+            Some(_) => {
+                return Err(anyhow!(
+                    "Knobs and faders can only be bound to volume control or balance"
+                ))
+            }
+            None => return Ok(()),
+        };
+
+        let position = value as f32 / 127.0;
+        let new_vol = match (options.encoder, options.pickup) {
+            (Some(encoder), _) => target
+                .volume(self.backend.as_mut(), options.aggregate)?
+                .map(|vol| options.position(vol) + encoder.delta(value)),
+            // Don't bother asking pulse for the current volume
+            (None, Pickup::Jump) => Some(position),
+            (None, pickup) => match target.volume(self.backend.as_mut(), options.aggregate)? {
+                Some(vol) => pickup.apply(
+                    last.map(|last| last as f32 / 127.0),
+                    position,
+                    options.position(vol),
+                ),
+                None => None,
+            },
+        };
+        if let Some(new_vol) = new_vol {
+            target.set_volume(self.backend.as_mut(), options.volume(new_vol))?;
         }
         Ok(())
     }
 
     pub fn btn_press(&mut self, btn: u8) -> Result<()> {
-        match self.bindings.get(&btn) {
+        let layer = &self.layers[self.active];
+        match layer.bindings.get(&Control::Button(btn)) {
             Some(MuteToggle(target)) => {
-                if let Some(muted) = target.toggle_muted(&mut self.sink, &mut self.source)? {
-                    self.midi_out.send(&[BTN_DOWN, btn, muted.into()])?;
+                if let Some(muted) = target.toggle_muted(self.backend.as_mut())? {
+                    send(&mut self.midi_out, layer.profile.button_led(btn, muted))?;
                 }
             }
-            Some(DefaultSelect(target)) => {
-                if let Some(_) = target.set_as_selected(&mut self.sink, &mut self.source)? {
-                    for (&c, binding) in &self.bindings {
-                        match binding {
-                            DefaultSelect(_) => {
-                                self.midi_out.send(&[BTN_DOWN, c, (c == btn).into()])?
+            Some(DefaultSelect(target, move_streams)) => {
+                if target
+                    .set_as_selected(self.backend.as_mut(), move_streams)?
+                    .is_some()
+                {
+                    // Sinks and sources each have their own default, so only the buttons for
+                    // the same kind of device make up a radio group
+                    let kind = target.device_kind();
+                    for (&c, binding) in &layer.bindings {
+                        match (c, binding) {
+                            (Control::Button(b), DefaultSelect(other, _))
+                                if other.device_kind() == kind =>
+                            {
+                                send(&mut self.midi_out, layer.profile.button_led(b, b == btn))?
                             }
                             _ => {}
                         }
                     }
                 }
             }
-            Some(VolumeControl(_getter)) => {
-                return Err(anyhow!("Buttons can not be bound to volume control"))
+            Some(Route(target, devices)) => {
+                if target.route(self.backend.as_mut(), devices)?.is_some() {
+                    let is_on = target.is_on(self.backend.as_mut(), &devices[0])?;
+                    send(
+                        &mut self.midi_out,
+                        layer.profile.button_led(btn, is_on.unwrap_or_default()),
+                    )?;
+                }
+            }
+            Some(Cycle(devices, move_streams, leds)) => {
+                let index = Target::cycle(self.backend.as_mut(), devices, move_streams)?;
+                if index.is_some() {
+                    send(
+                        &mut self.midi_out,
+                        cycle_led(&layer.profile, btn, leds, index),
+                    )?;
+                }
+            }
+            Some(CardProfile(card, profiles)) => {
+                if card
+                    .switch_profile(self.backend.as_mut(), profiles)?
+                    .is_some()
+                {
+                    let active = card.active_profile(self.backend.as_mut())?;
+                    send(
+                        &mut self.midi_out,
+                        layer
+                            .profile
+                            .button_led(btn, active.as_ref() == Some(&profiles[0])),
+                    )?;
+                }
+            }
+            Some(Port(device, ports)) => {
+                if device.switch_port(self.backend.as_mut(), ports)?.is_some() {
+                    let active = device.active_port(self.backend.as_mut())?;
+                    send(
+                        &mut self.midi_out,
+                        layer
+                            .profile
+                            .button_led(btn, active.as_ref() == Some(&ports[0])),
+                    )?;
+                }
+            }
+            Some(VolumeControl(..)) | Some(Balance(_)) => {
+                return Err(anyhow!(
+                    "Buttons can not be bound to volume control or balance"
+                ))
             }
             None => { /* unbound button, do nothing */ }
         }
@@ -119,15 +352,463 @@ impl Deck {
         Ok(())
     }
 
+    fn handle_input(&mut self, layer: usize, input: Input) -> Result<()> {
+        if layer != self.active {
+            self.switch_layer(layer)?;
+        }
+        match input {
+            Input::Knob(knob, value) => self.knob_update(knob, value),
+            Input::ButtonDown => Ok(()),
+            Input::ButtonUp(btn) => self.btn_press(btn),
+            Input::Fader(fader, value) => self.fader_update(fader, value),
+        }
+    }
+
     pub fn handle_midi_message(&mut self, message: &[u8]) -> Result<()> {
-        match message {
-            [KNOB_UPDATE, knob, value] => self.knob_update(*knob, *value),
-            [BTN_DOWN, _btn, _value] => Ok(()),
-            [BTN_UP, btn, _value] => self.btn_press(*btn),
-            _ => {
+        // The board is redrawn from pulse once reconnected, so input in the meantime is dropped
+        if !self.connected {
+            return Ok(());
+        }
+        // Prefer the active layer, in case several layers send the same messages
+        let decoded = std::iter::once(self.active)
+            .chain(0..self.layers.len())
+            .find_map(|i| Some((i, self.layers[i].profile.decode(message)?)));
+        let (layer, input) = match decoded {
+            Some(decoded) => decoded,
+            None => {
                 println!("Unknown message: {:?}", message);
-                Ok(())
+                return Ok(());
+            }
+        };
+        let result = self.handle_input(layer, input);
+        self.check_connection(result)
+    }
+}
+
+/// Sends to the board if it is connected and the control has an LED, otherwise does nothing
+fn send(midi_out: &mut Option<Box<dyn MidiSink>>, message: Option<[u8; 3]>) -> Result<()> {
+    if let (Some(midi_out), Some(message)) = (midi_out, message) {
+        midi_out.send(&message)?;
+    }
+    Ok(())
+}
+
+/// Sends the LEDs of a bound control to match what it is bound to
+fn flush_control(
+    backend: &mut dyn AudioBackend,
+    midi_out: &mut Option<Box<dyn MidiSink>>,
+    positions: &mut HashMap<Control, u8>,
+    layer: &Layer,
+    control: Control,
+) -> Result<()> {
+    let led = match (control, layer.bindings.get(&control)) {
+        (Control::Knob(knob), Some(Binding::VolumeControl(target, options))) => {
+            let val = match target.volume(backend, options.aggregate)? {
+                Some(vol) => position_to_value(options.position(vol)),
+                None => 0,
+            };
+            return send_knob(midi_out, positions, &layer.profile, knob, val);
+        }
+        (Control::Knob(knob), Some(Binding::Balance(target))) => {
+            let val = match target.balance(backend)? {
+                Some(balance) => balance_to_value(balance),
+                None => 0,
+            };
+            return send_knob(midi_out, positions, &layer.profile, knob, val);
+        }
+        (Control::Button(button), Some(Binding::MuteToggle(target))) => {
+            let is_muted = target.muted(backend)?.unwrap_or_default();
+            layer.profile.button_led(button, is_muted)
+        }
+        (Control::Button(button), Some(Binding::Route(target, devices))) => {
+            let is_on = target.is_on(backend, &devices[0])?;
+            layer.profile.button_led(button, is_on.unwrap_or_default())
+        }
+        (Control::Button(button), Some(Binding::DefaultSelect(target, _))) => {
+            let is_selected = target.selected(backend)?.unwrap_or_default();
+            layer.profile.button_led(button, is_selected)
+        }
+        (Control::Button(button), Some(Binding::CardProfile(card, profiles))) => {
+            let active = card.active_profile(backend)?;
+            layer
+                .profile
+                .button_led(button, active.as_ref() == Some(&profiles[0]))
+        }
+        (Control::Button(button), Some(Binding::Port(device, ports))) => {
+            let active = device.active_port(backend)?;
+            layer
+                .profile
+                .button_led(button, active.as_ref() == Some(&ports[0]))
+        }
+        (Control::Button(button), Some(Binding::Cycle(devices, _, leds))) => {
+            let index = Target::selected_index(backend, devices)?;
+            cycle_led(&layer.profile, button, leds, index)
+        }
+        _ => None,
+    };
+    send(midi_out, led)
+}
+
+/// The LED of a cycle button while `index` is the current default. Without `leds` it is on
+/// whenever one of the devices is the default, and it is off when none of them is.
+fn cycle_led(profile: &Profile, button: u8, leds: &[u8], index: Option<usize>) -> Option<[u8; 3]> {
+    match index {
+        Some(i) if !leds.is_empty() => profile.button_led_value(button, leds[i]),
+        Some(_) => profile.button_led(button, true),
+        None => profile.button_led(button, false),
+    }
+}
+
+/// Moves a knob's LED ring, which also moves the knob itself on boards with endless encoders
+fn send_knob(
+    midi_out: &mut Option<Box<dyn MidiSink>>,
+    positions: &mut HashMap<Control, u8>,
+    profile: &Profile,
+    knob: u8,
+    value: u8,
+) -> Result<()> {
+    let message = profile.knob_led(knob, value);
+    if midi_out.is_some() && message.is_some() {
+        positions.insert(Control::Knob(knob), value);
+    }
+    send(midi_out, message)
+}
+
+/// The CC value of a knob at `position`, from 0 to 1
+fn position_to_value(position: f32) -> u8 {
+    (position * 127.0).round() as u8
+}
+
+/// 64 is centered, as the knob's range has no middle value
+fn value_to_balance(value: u8) -> f32 {
+    ((value as f32 - 64.0) / 63.0).clamp(-1.0, 1.0)
+}
+
+fn balance_to_value(balance: f32) -> u8 {
+    (balance * 63.0 + 64.0).round().clamp(0.0, 127.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{Device, DeviceKind, Stream, StreamKind},
+        binding::{Curve, VolumeOptions},
+        fake_backend::{device, stream, FakeBackend},
+        midi::RecordingMidiSink,
+    };
+    use pulse::volume::Volume;
+
+    const CONFIG: &str = r#"
+        [targets]
+        speakers = { sink = "speakers" }
+        headphones = { sink = "headphones" }
+        mic = { source = "mic" }
+        music = { sink_input = { "application.name" = "mpv" } }
+
+        [knobs]
+        11 = { volume = { ref = "speakers" } }
+        12 = { volume = { ref = "music" } }
+
+        [buttons]
+        32 = { select = { ref = "speakers" } }
+        33 = { select = { ref = "headphones" }, move_streams = true }
+        34 = { select = { ref = "mic" } }
+        40 = { mute = { ref = "speakers" } }
+        41 = { mute = { ref = "music" } }
+        35 = { route = { ref = "music" }, to = [{ ref = "headphones" }, { ref = "speakers" }] }
+        36 = { cycle = [{ ref = "speakers" }, { ref = "headphones" }], leds = [5, 9] }
+    "#;
+
+    fn deck() -> Deck {
+        let backend = FakeBackend {
+            sinks: vec![device(1, "speakers"), device(2, "headphones")],
+            sources: vec![device(3, "mic")],
+            default_sink: Some(1),
+            default_source: Some(3),
+            sink_inputs: vec![
+                stream(10, 1, &[("application.name", "mpv")]),
+                stream(11, 1, &[("application.name", "firefox")]),
+            ],
+            ..FakeBackend::default()
+        };
+        let mut deck = Deck::new(Box::new(backend));
+        deck.load_config(&Config::parse(CONFIG).unwrap()).unwrap();
+        deck
+    }
+
+    fn sink(deck: &mut Deck, index: u32) -> Device {
+        let sinks = deck.backend.devices(DeviceKind::Sink).unwrap();
+        sinks.into_iter().find(|d| d.index == index).unwrap()
+    }
+
+    fn sink_input(deck: &mut Deck, index: u32) -> Stream {
+        let streams = deck.backend.streams(StreamKind::SinkInput).unwrap();
+        streams.into_iter().find(|s| s.index == index).unwrap()
+    }
+
+    fn default_sink(deck: &mut Deck) -> u32 {
+        let default = deck.backend.default_device(DeviceKind::Sink).unwrap();
+        default.unwrap().index
+    }
+
+    /// The volume of a knob at `value` with the default options
+    fn knob_volume(value: u8) -> Volume {
+        VolumeOptions::default().volume(value as f32 / 127.0)
+    }
+
+    /// Plugs in a board that records what is sent to it, forgetting the initial redraw
+    fn connect(deck: &mut Deck) -> RecordingMidiSink {
+        let board = RecordingMidiSink::default();
+        deck.set_midi_out(Some(Box::new(board.clone()))).unwrap();
+        board.sent.borrow_mut().clear();
+        board
+    }
+
+    /// What was sent since the last call, sorted as bindings are flushed in no particular order
+    fn sent(board: &RecordingMidiSink) -> Vec<Vec<u8>> {
+        let mut sent = board.sent.take();
+        sent.sort();
+        sent
+    }
+
+    #[test]
+    fn knob_sets_device_volume() {
+        let mut deck = deck();
+        deck.knob_update(11, 64).unwrap();
+        assert_eq!(sink(&mut deck, 1).volume.max(), knob_volume(64));
+        assert_eq!(sink(&mut deck, 2).volume.max(), Volume::NORMAL);
+        assert_eq!(sink_input(&mut deck, 10).volume.max(), Volume::NORMAL);
+    }
+
+    #[test]
+    fn knob_sets_volume_of_matching_streams() {
+        let mut deck = deck();
+        deck.knob_update(12, 32).unwrap();
+        assert_eq!(sink_input(&mut deck, 10).volume.max(), knob_volume(32));
+        assert_eq!(sink_input(&mut deck, 11).volume.max(), Volume::NORMAL);
+        assert_eq!(sink(&mut deck, 1).volume.max(), Volume::NORMAL);
+    }
+
+    #[test]
+    fn unbound_knob_changes_nothing() {
+        let mut deck = deck();
+        deck.knob_update(13, 10).unwrap();
+        assert_eq!(sink(&mut deck, 1).volume.max(), Volume::NORMAL);
+        assert_eq!(sink_input(&mut deck, 10).volume.max(), Volume::NORMAL);
+    }
+
+    #[test]
+    fn mute_press_toggles_device() {
+        let mut deck = deck();
+        deck.btn_press(40).unwrap();
+        assert!(sink(&mut deck, 1).mute);
+        assert!(!sink(&mut deck, 2).mute);
+        deck.btn_press(40).unwrap();
+        assert!(!sink(&mut deck, 1).mute);
+    }
+
+    #[test]
+    fn mute_press_toggles_matching_streams() {
+        let mut deck = deck();
+        deck.btn_press(41).unwrap();
+        assert!(sink_input(&mut deck, 10).mute);
+        assert!(!sink_input(&mut deck, 11).mute);
+        assert!(!sink(&mut deck, 1).mute);
+    }
+
+    #[test]
+    fn select_press_sets_default_and_moves_streams() {
+        let mut deck = deck();
+        deck.btn_press(33).unwrap();
+        assert_eq!(default_sink(&mut deck), 2);
+        assert_eq!(sink_input(&mut deck, 10).device, 2);
+        assert_eq!(sink_input(&mut deck, 11).device, 2);
+
+        // Without move_streams the streams stay where they are
+        deck.btn_press(32).unwrap();
+        assert_eq!(default_sink(&mut deck), 1);
+        assert_eq!(sink_input(&mut deck, 10).device, 2);
+        let default_source = deck.backend.default_device(DeviceKind::Source).unwrap();
+        assert_eq!(default_source.unwrap().index, 3);
+    }
+
+    #[test]
+    fn flush_leaves_volumes_alone() {
+        let mut deck = deck();
+        deck.knob_update(11, 100).unwrap();
+        deck.btn_press(40).unwrap();
+        deck.flush_values_to_board().unwrap();
+        assert_eq!(sink(&mut deck, 1).volume.max(), knob_volume(100));
+        assert!(sink(&mut deck, 1).mute);
+        assert_eq!(default_sink(&mut deck), 1);
+    }
+
+    #[test]
+    fn knob_values_round_trip_through_volume() {
+        for curve in [Curve::Cubic, Curve::Linear, Curve::Db(-60.0)] {
+            for max_volume in [1.0, 1.5] {
+                let options = VolumeOptions {
+                    curve,
+                    max_volume,
+                    ..VolumeOptions::default()
+                };
+                for value in 0..=127u8 {
+                    let volume = options.volume(value as f32 / 127.0);
+                    assert_eq!(
+                        position_to_value(options.position(volume)),
+                        value,
+                        "{:?} curve up to {}",
+                        curve,
+                        max_volume
+                    );
+                }
             }
         }
     }
+
+    #[test]
+    fn clear_blanks_every_control() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.clear().unwrap();
+        let knobs = (11..=18u8).map(|knob| vec![0xBA, knob, 0]);
+        let buttons = (24..=47u8).map(|button| vec![0x9A, button, 0]);
+        assert_eq!(
+            *board.sent.borrow(),
+            knobs.chain(buttons).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn flush_sends_every_binding() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.flush_values_to_board().unwrap();
+        assert_eq!(
+            sent(&board),
+            vec![
+                vec![0x9A, 32, 1],
+                vec![0x9A, 33, 0],
+                vec![0x9A, 34, 1],
+                vec![0x9A, 35, 0],
+                vec![0x9A, 36, 5],
+                vec![0x9A, 40, 0],
+                vec![0x9A, 41, 0],
+                vec![0xBA, 11, 127],
+                vec![0xBA, 12, 127],
+            ]
+        );
+    }
+
+    #[test]
+    fn flush_without_board_sends_nothing() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.set_midi_out(None).unwrap();
+        deck.flush_values_to_board().unwrap();
+        assert_eq!(sent(&board), Vec::<Vec<u8>>::new());
+    }
+
+    #[test]
+    fn pulse_event_refreshes_affected_controls() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.knob_update(11, 64).unwrap();
+        deck.handle_pulse_events(&[PulseEvent::Sink]).unwrap();
+        assert_eq!(
+            sent(&board),
+            vec![
+                vec![0x9A, 32, 1],
+                vec![0x9A, 33, 0],
+                vec![0x9A, 35, 0],
+                vec![0x9A, 36, 5],
+                vec![0x9A, 40, 0],
+                vec![0xBA, 11, 64],
+            ]
+        );
+    }
+
+    #[test]
+    fn pulse_events_are_handled_together() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        let events = [PulseEvent::Sink, PulseEvent::SinkInput, PulseEvent::Sink];
+        deck.handle_pulse_events(&events).unwrap();
+        assert_eq!(
+            sent(&board),
+            vec![
+                vec![0x9A, 32, 1],
+                vec![0x9A, 33, 0],
+                vec![0x9A, 35, 0],
+                vec![0x9A, 36, 5],
+                vec![0x9A, 40, 0],
+                vec![0x9A, 41, 0],
+                vec![0xBA, 11, 127],
+                vec![0xBA, 12, 127],
+            ]
+        );
+    }
+
+    #[test]
+    fn mute_press_lights_button() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.btn_press(40).unwrap();
+        assert_eq!(sent(&board), vec![vec![0x9A, 40, 1]]);
+        deck.btn_press(40).unwrap();
+        assert_eq!(sent(&board), vec![vec![0x9A, 40, 0]]);
+    }
+
+    #[test]
+    fn select_press_lights_its_radio_group() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        // The mic is a source, so its button isn't in the same group
+        deck.btn_press(33).unwrap();
+        assert_eq!(sent(&board), vec![vec![0x9A, 32, 0], vec![0x9A, 33, 1]]);
+        deck.btn_press(32).unwrap();
+        assert_eq!(sent(&board), vec![vec![0x9A, 32, 1], vec![0x9A, 33, 0]]);
+    }
+
+    #[test]
+    fn route_press_lights_button_on_first_device() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.btn_press(35).unwrap();
+        assert_eq!(sink_input(&mut deck, 10).device, 2);
+        assert_eq!(sent(&board), vec![vec![0x9A, 35, 1]]);
+        deck.btn_press(35).unwrap();
+        assert_eq!(sink_input(&mut deck, 10).device, 1);
+        assert_eq!(sent(&board), vec![vec![0x9A, 35, 0]]);
+    }
+
+    #[test]
+    fn cycle_press_sends_led_value_of_new_default() {
+        let mut deck = deck();
+        let board = connect(&mut deck);
+        deck.btn_press(36).unwrap();
+        assert_eq!(default_sink(&mut deck), 2);
+        assert_eq!(sent(&board), vec![vec![0x9A, 36, 9]]);
+        deck.btn_press(36).unwrap();
+        assert_eq!(default_sink(&mut deck), 1);
+        assert_eq!(sent(&board), vec![vec![0x9A, 36, 5]]);
+    }
+
+    #[test]
+    fn cycle_without_leds_lights_while_any_device_is_default() {
+        let mut deck = deck();
+        let config = r#"
+            [buttons]
+            37 = { cycle = [{ sink = "headphones" }, { sink = "speakers" }] }
+            38 = { cycle = [{ sink = "headphones" }] }
+        "#;
+        deck.load_config(&Config::parse(config).unwrap()).unwrap();
+        let board = connect(&mut deck);
+        deck.flush_values_to_board().unwrap();
+        assert_eq!(sent(&board), vec![vec![0x9A, 37, 1], vec![0x9A, 38, 0]]);
+        deck.btn_press(37).unwrap();
+        assert_eq!(default_sink(&mut deck), 2);
+        assert_eq!(sent(&board), vec![vec![0x9A, 37, 1]]);
+    }
 }
@@ -0,0 +1,124 @@
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, Result};
+use pulse::context::subscribe::{Facility, InterestMaskSet};
+use pulse::context::{Context, FlagSet, State};
+use pulse::mainloop::standard::{IterateResult, Mainloop};
+
+/// How long to wait before reconnecting to pulse, doubled after every failed attempt up to
+/// `MAX_BACKOFF`. Shared with the deck, which keeps its own connection.
+pub const MIN_BACKOFF: Duration = Duration::from_millis(250);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Something on the pulse server changed. Targets look devices and streams up by name or
+/// property, so the index of what changed isn't needed.
+#[derive(Clone, Copy, Debug)]
+pub enum PulseEvent {
+    Sink,
+    Source,
+    SinkInput,
+    SourceOutput,
+    /// A card's profiles or ports changed
+    Card,
+    /// Server wide settings such as the default sink or source changed
+    Server,
+    /// The server is reachable again after the connection was lost
+    Connected,
+    Disconnected,
+}
+
+/// Calls `on_event` for every change to sinks, sources, their streams, cards and server settings.
+/// When the server goes away (e.g. pipewire-pulse restarting) this keeps trying to reconnect,
+/// backing off exponentially, so it never returns and should get its own thread.
+pub fn listen<F>(mut on_event: F) -> !
+where
+    F: FnMut(PulseEvent) + Clone + 'static,
+{
+    let mut backoff = MIN_BACKOFF;
+    let mut was_connected = true;
+    loop {
+        match connect() {
+            Ok((mainloop, context)) => {
+                backoff = MIN_BACKOFF;
+                if !was_connected {
+                    println!("Reconnected to pulse");
+                    on_event(PulseEvent::Connected);
+                }
+                was_connected = true;
+                let e = subscribe(mainloop, context, on_event.clone());
+                eprintln!("Lost connection to pulse: {:#}", e);
+            }
+            Err(e) => eprintln!("Failed to connect to pulse: {:#}", e),
+        }
+        if was_connected {
+            on_event(PulseEvent::Disconnected);
+            was_connected = false;
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+fn connect() -> Result<(Mainloop, Context)> {
+    let mut mainloop = Mainloop::new().ok_or_else(|| anyhow!("Failed to create pulse mainloop"))?;
+    let mut context = Context::new(&mainloop, "DreamDeck events")
+        .ok_or_else(|| anyhow!("Failed to create pulse context"))?;
+    context
+        .connect(None, FlagSet::NOFLAGS, None)
+        .map_err(|e| anyhow!("Failed to connect to pulse: {:?}", e))?;
+
+    loop {
+        iterate(&mut mainloop)?;
+        match context.get_state() {
+            State::Ready => return Ok((mainloop, context)),
+            State::Failed | State::Terminated => return Err(anyhow!("Failed to connect to pulse")),
+            _ => {}
+        }
+    }
+}
+
+/// Forwards events until the connection is lost, returning why
+fn subscribe(
+    mut mainloop: Mainloop,
+    mut context: Context,
+    mut on_event: impl FnMut(PulseEvent) + 'static,
+) -> anyhow::Error {
+    context.set_subscribe_callback(Some(Box::new(move |facility, _operation, _index| {
+        let event = match facility {
+            Some(Facility::Sink) => PulseEvent::Sink,
+            Some(Facility::Source) => PulseEvent::Source,
+            Some(Facility::SinkInput) => PulseEvent::SinkInput,
+            Some(Facility::SourceOutput) => PulseEvent::SourceOutput,
+            Some(Facility::Card) => PulseEvent::Card,
+            Some(Facility::Server) => PulseEvent::Server,
+            _ => return,
+        };
+        on_event(event);
+    })));
+    context.subscribe(
+        InterestMaskSet::SINK
+            | InterestMaskSet::SOURCE
+            | InterestMaskSet::SINK_INPUT
+            | InterestMaskSet::SOURCE_OUTPUT
+            | InterestMaskSet::CARD
+            | InterestMaskSet::SERVER,
+        |_| {},
+    );
+
+    loop {
+        if let Err(e) = iterate(&mut mainloop) {
+            return e;
+        }
+        if context.get_state() != State::Ready {
+            return anyhow!("Connection closed");
+        }
+    }
+}
+
+fn iterate(mainloop: &mut Mainloop) -> Result<()> {
+    match mainloop.iterate(true) {
+        IterateResult::Success(_) => Ok(()),
+        IterateResult::Quit(_) => Err(anyhow!("Pulse mainloop quit")),
+        IterateResult::Err(e) => Err(anyhow!("Pulse mainloop error: {:?}", e)),
+    }
+}
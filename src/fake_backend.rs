@@ -0,0 +1,196 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use pulse::{
+    channelmap::Map,
+    volume::{ChannelVolumes, Volume},
+};
+
+use crate::backend::{AudioBackend, Card, Device, DeviceKind, Stream, StreamKind};
+
+/// Keeps everything in memory, for exercising bindings without a sound server
+#[derive(Default)]
+pub struct FakeBackend {
+    pub sinks: Vec<Device>,
+    pub sources: Vec<Device>,
+    pub default_sink: Option<u32>,
+    pub default_source: Option<u32>,
+    pub sink_inputs: Vec<Stream>,
+    pub source_outputs: Vec<Stream>,
+    pub cards: Vec<Card>,
+    /// How many times devices, streams or cards were listed
+    pub lists: usize,
+}
+
+impl FakeBackend {
+    fn devices_mut(&mut self, kind: DeviceKind) -> &mut Vec<Device> {
+        match kind {
+            DeviceKind::Sink => &mut self.sinks,
+            DeviceKind::Source => &mut self.sources,
+        }
+    }
+
+    fn device_mut(&mut self, kind: DeviceKind, index: u32) -> Result<&mut Device> {
+        self.devices_mut(kind)
+            .iter_mut()
+            .find(|d| d.index == index)
+            .ok_or_else(|| anyhow!("No {:?} with index {}", kind, index))
+    }
+
+    fn streams_mut(&mut self, kind: StreamKind) -> &mut Vec<Stream> {
+        match kind {
+            StreamKind::SinkInput => &mut self.sink_inputs,
+            StreamKind::SourceOutput => &mut self.source_outputs,
+        }
+    }
+
+    fn stream_mut(&mut self, kind: StreamKind, index: u32) -> Result<&mut Stream> {
+        self.streams_mut(kind)
+            .iter_mut()
+            .find(|s| s.index == index)
+            .ok_or_else(|| anyhow!("No {:?} with index {}", kind, index))
+    }
+}
+
+impl AudioBackend for FakeBackend {
+    fn devices(&mut self, kind: DeviceKind) -> Result<Vec<Device>> {
+        self.lists += 1;
+        Ok(self.devices_mut(kind).clone())
+    }
+
+    fn default_device(&mut self, kind: DeviceKind) -> Result<Option<Device>> {
+        let default = match kind {
+            DeviceKind::Sink => self.default_sink,
+            DeviceKind::Source => self.default_source,
+        };
+        Ok(default.and_then(|index| self.device_mut(kind, index).ok().cloned()))
+    }
+
+    fn set_default_device(&mut self, kind: DeviceKind, name: &str) -> Result<()> {
+        let index = self
+            .devices_mut(kind)
+            .iter()
+            .find(|d| d.name.as_deref() == Some(name))
+            .map(|d| d.index)
+            .ok_or_else(|| anyhow!("No {:?} named {}", kind, name))?;
+        match kind {
+            DeviceKind::Sink => self.default_sink = Some(index),
+            DeviceKind::Source => self.default_source = Some(index),
+        }
+        Ok(())
+    }
+
+    fn set_device_volume(
+        &mut self,
+        kind: DeviceKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        self.device_mut(kind, index)?.volume = *volume;
+        Ok(())
+    }
+
+    fn set_device_mute(&mut self, kind: DeviceKind, index: u32, mute: bool) -> Result<()> {
+        self.device_mut(kind, index)?.mute = mute;
+        Ok(())
+    }
+
+    fn set_device_port(&mut self, kind: DeviceKind, index: u32, port: &str) -> Result<()> {
+        let device = self.device_mut(kind, index)?;
+        if !device.ports.iter().any(|p| p == port) {
+            bail!("{:?} {} has no port {}", kind, index, port);
+        }
+        device.active_port = Some(port.to_owned());
+        Ok(())
+    }
+
+    fn streams(&mut self, kind: StreamKind) -> Result<Vec<Stream>> {
+        self.lists += 1;
+        Ok(self.streams_mut(kind).clone())
+    }
+
+    fn set_stream_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        self.stream_mut(kind, index)?.volume = *volume;
+        Ok(())
+    }
+
+    fn set_stream_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<()> {
+        self.stream_mut(kind, index)?.mute = mute;
+        Ok(())
+    }
+
+    fn move_stream(&mut self, kind: StreamKind, index: u32, device: u32) -> Result<()> {
+        self.stream_mut(kind, index)?.device = device;
+        Ok(())
+    }
+
+    fn cards(&mut self) -> Result<Vec<Card>> {
+        self.lists += 1;
+        Ok(self.cards.clone())
+    }
+
+    fn set_card_profile(&mut self, index: u32, profile: &str) -> Result<()> {
+        let card = self
+            .cards
+            .iter_mut()
+            .find(|c| c.index == index)
+            .ok_or_else(|| anyhow!("No card with index {}", index))?;
+        if !card.profiles.iter().any(|p| p == profile) {
+            bail!("Card {} has no profile {}", index, profile);
+        }
+        card.active_profile = Some(profile.to_owned());
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A stereo sink or source at 100%
+pub fn device(index: u32, name: &str) -> Device {
+    let (volume, channel_map) = stereo();
+    Device {
+        index,
+        name: Some(name.to_owned()),
+        volume,
+        channel_map,
+        mute: false,
+        properties: HashMap::new(),
+        ports: Vec::new(),
+        active_port: None,
+    }
+}
+
+/// A stereo stream at 100% on `device`
+pub fn stream(index: u32, device: u32, properties: &[(&str, &str)]) -> Stream {
+    let (volume, channel_map) = stereo();
+    Stream {
+        index,
+        device,
+        volume,
+        channel_map,
+        mute: false,
+        properties: properties
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+    }
+}
+
+fn stereo() -> (ChannelVolumes, Map) {
+    let mut channel_map = Map::default();
+    channel_map.init_stereo();
+    let mut volume = ChannelVolumes::default();
+    volume.set(channel_map.len(), Volume::NORMAL);
+    (volume, channel_map)
+}
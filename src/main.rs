@@ -1,172 +1,232 @@
+mod backend;
 mod binding;
+mod config;
 mod deck;
+mod events;
+#[cfg(test)]
+mod fake_backend;
+mod matcher;
+mod midi;
+mod profile;
+mod pulse_backend;
 mod target;
 
 use core::time;
 
-use std::{collections::HashMap, thread};
+use std::thread;
 
-use pulse::proplist::properties::APPLICATION_NAME;
-use pulse::proplist::properties::APPLICATION_PROCESS_BINARY;
-use pulse::proplist::properties::MEDIA_NAME;
-use pulsectl::controllers::DeviceControl;
-use pulsectl::controllers::SinkController;
-use pulsectl::controllers::SourceController;
-use std::sync::mpsc::channel;
+use anyhow::{anyhow, bail, Result};
+use config::Config;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Sender};
 
-use midir::{MidiInput, MidiOutput};
-
-use anyhow::Result;
-use binding::Binding;
+use midir::MidiInputConnection;
 
 use deck::Deck;
+use events::PulseEvent;
+use midi::PortPattern;
+use pulse_backend::PulseBackend;
+
+enum Msg {
+    PulseUpdate(PulseEvent),
+    MidiUpdate(Vec<u8>),
+    /// Periodic housekeeping: check whether the board has been unplugged or plugged back in,
+    /// and retry connecting to pulse if needed
+    Tick,
+    ReloadConfig,
+}
+
+struct Args {
+    config_path: PathBuf,
+    list_ports: bool,
+    device: Option<String>,
+    input: Option<String>,
+    output: Option<String>,
+}
 
-use target::Target::*;
-
-const SPEAKER_SINK : &str = "alsa_output.usb-Apple__Inc._USB-C_to_3.5mm_Headphone_Jack_Adapter_DWH931705N1JKLTAL-00.analog-stereo";
-const HEADPHONE_SINK :&str = "alsa_output.usb-Apple__Inc._USB-C_to_3.5mm_Headphone_Jack_Adapter_DWH9317032QJKLTAR-00.analog-stereo";
-const MIC_SOURCE :&str ="alsa_input.usb-Apple__Inc._USB-C_to_3.5mm_Headphone_Jack_Adapter_DWH9317032QJKLTAR-00.mono-fallback";
-// const LINEIN_SOURCE: &str = "alsa_input.pci-0000_00_1f.3.analog-stereo";
-
-fn make_config(
-    sink_controller: &mut SinkController,
-    source_controller: &mut SourceController,
-) -> Result<HashMap<u8, Binding>> {
-    let speakers = sink_controller.get_device_by_name(SPEAKER_SINK)?;
-    let headphones = sink_controller.get_device_by_name(HEADPHONE_SINK)?;
-    let mic = source_controller.get_device_by_name(MIC_SOURCE)?;
-
-    // Layer B
-    //
-    // (11) (12) (13) (14) (15) (16) (17) (18)  Knob Turn
-    // [24] [25] [26] [27] [28] [29] [30] [31]  Knob Press
-    //
-    // [32] [33] [34] [35] [36] [37] [38] [39]  Buttons
-    // [40] [41] [42] [43] [44] [45] [46] [47]
-
-    let mut bindings = HashMap::from([
-        (11, Binding::volume(StaticSink(speakers.index))),
-        (12, Binding::volume(StaticSink(headphones.index))),
-        (
-            13,
-            Binding::volume(Any(vec![
-                SinkWithProperty(APPLICATION_NAME, "WEBRTC VoiceEngine"), // Discord
-                SinkWithProperty(APPLICATION_NAME, "ZOOM VoiceEngine"),
-            ])),
-        ),
-        (
-            14,
-            Binding::volume(Any(vec![
-                // TODO control multiple targets with 1 knob/button
-                //             sink_getter_all_by_property("application.name", "FINAL FANTASY XIV"),
-                SinkWithProperty(APPLICATION_NAME, "ALSA plug-in [wine64-preloader]"),
-                SinkWithProperty(APPLICATION_NAME, "Among Us.exe"),
-                SinkWithProperty(APPLICATION_NAME, "Spel2.exe"), // Spelunky 2
-                SinkWithProperty(APPLICATION_NAME, "FMOD Ex App"),
-                SinkWithProperty(APPLICATION_NAME, "Risk of Rain 2.exe"),
-                SinkWithProperty(APPLICATION_PROCESS_BINARY, "DyingLightGame"),
-                // Generic games running under wine
-                SinkWithProperty(APPLICATION_NAME, "wine-preloader"),
-                SinkWithProperty(APPLICATION_NAME, "wine64-preloader"),
-                SinkWithProperty(APPLICATION_PROCESS_BINARY, "wine-preloader"),
-                SinkWithProperty(APPLICATION_PROCESS_BINARY, "wine64-preloader"),
-                // Steam Streaming
-                SinkWithProperty(APPLICATION_PROCESS_BINARY, "streaming_client"),
-            ])),
-        ),
-        (
-            15,
-            Binding::volume(Any(vec![
-                SinkWithProperty(APPLICATION_NAME, "Google Play Music Desktop Player"),
-                SinkWithProperty(APPLICATION_NAME, "mpv Media Player"),
-            ])),
-        ),
-        (
-            16,
-            Binding::volume(SinkWithProperty(MEDIA_NAME, "Loopback of Onboard Audio")),
-        ),
-        (
-            17,
-            Binding::volume(SinkWithProperty(APPLICATION_NAME, "Moonlight")),
-        ),
-        // (
-        //     18,
-        //     Binding::volume(All(vec![
-        //         StaticSink(speakers.index),
-        //         StaticSink(headphones.index),
-        //     ])),
-        // ),
-        (32, Binding::select(StaticSink(speakers.index))),
-        (33, Binding::select(StaticSink(headphones.index))),
-        (34, Binding::mute(StaticSource(mic.index))),
-    ]);
-
-    // Bind the bottom row of buttons to mute the thing the knob in that column controls the volume of
-    for i in 0..=6 {
-        bindings.insert(40 + i, bindings.get(&(11 + i)).unwrap().to_mute());
+impl Args {
+    const USAGE: &'static str = "Usage: dreamdeck [--list-ports] [--device PATTERN] \
+        [--input PATTERN] [--output PATTERN] [CONFIG]";
+
+    fn parse() -> Result<Self> {
+        let mut args = Args {
+            config_path: config::default_path(),
+            list_ports: false,
+            device: None,
+            input: None,
+            output: None,
+        };
+
+        let mut argv = env::args_os().skip(1);
+        while let Some(arg) = argv.next() {
+            let mut value = || {
+                argv.next()
+                    .and_then(|v| v.into_string().ok())
+                    .ok_or_else(|| anyhow!("{:?} needs a value\n{}", arg, Self::USAGE))
+            };
+            match arg.to_str() {
+                Some("--list-ports") => args.list_ports = true,
+                Some("--device") => args.device = Some(value()?),
+                Some("--input") => args.input = Some(value()?),
+                Some("--output") => args.output = Some(value()?),
+                Some(flag) if flag.starts_with('-') => {
+                    bail!("Unknown option {}\n{}", flag, Self::USAGE)
+                }
+                _ => args.config_path = PathBuf::from(&arg),
+            }
+        }
+
+        Ok(args)
     }
-    // bindings.insert(47, bindings.get(&(18)).unwrap().to_mute());
+}
+
+/// Forwards messages from the board to the main thread
+fn forward_midi(tx: Sender<Msg>) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    move |_stamp, message, _| {
+        println!("{}: {:?} (len = {})", _stamp, message, message.len());
 
-    Ok(bindings)
+        // Not every message is 3 bytes long, e.g. clock and active sensing, so leave it to the
+        // profile to pick out the ones it knows
+        tx.send(Msg::MidiUpdate(message.to_vec()))
+            .expect("failed to send midi message to main thread");
+    }
 }
 
-enum Msg {
-    SyncBoard,
-    MidiUpdate([u8; 3]),
+fn connect_midi(
+    device: &midi::Device,
+    deck: &mut Deck,
+    tx: &Sender<Msg>,
+) -> Option<MidiInputConnection<()>> {
+    let (midi_in, midi_out) = match device.connect(forward_midi(tx.clone())) {
+        Ok(connection) => connection,
+        Err(e) => {
+            eprintln!("MIDI device not connected, waiting for it: {:#}", e);
+            return None;
+        }
+    };
+    println!("Connected to MIDI device");
+    // The board is connected either way, a binding failing to redraw shouldn't disconnect it
+    if let Err(e) = deck.set_midi_out(Some(Box::new(midi_out))) {
+        eprintln!("Failed to redraw the board: {:#}", e);
+    }
+    Some(midi_in)
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let midi_out = MidiOutput::new("DreamDeck out")?;
-    let port = &midi_out.ports()[1];
-    let midi_out = midi_out.connect(port, "DreamDeck write")?;
+    let args = Args::parse()?;
+    if args.list_ports {
+        return Ok(midi::list_ports()?);
+    }
 
-    let midi_in = MidiInput::new("DreamDeak in")?;
-    // midi_in.ignore(Ignore::None);
-    let in_ports = midi_in.ports();
+    let config_path = args.config_path;
+    let config = Config::load(&config_path)?;
+
+    // Ports given on the command line win over the config file
+    let device = args.device.or_else(|| config.midi.device.clone());
+    let device = device.as_deref().unwrap_or(midi::DEFAULT_DEVICE);
+    let output = args.output.or_else(|| config.midi.output.clone());
+    let input = args.input.or_else(|| config.midi.input.clone());
+    let midi_device = midi::Device::new(
+        PortPattern::new(input.as_deref().unwrap_or(device))?,
+        PortPattern::new(output.as_deref().unwrap_or(device))?,
+    )?;
 
     let (tx, rx) = channel();
 
-    let midi_tx = tx.clone();
-    let _midi_in = midi_in.connect(
-        &in_ports[1],
-        "DreamDeck read",
-        move |_stamp, message, _| {
-            println!("{}: {:?} (len = {})", _stamp, message, message.len());
-
-            midi_tx
-                .send(Msg::MidiUpdate([message[0], message[1], message[2]]))
-                .expect("failed to send midi message to main thread");
-        },
-        (),
-    )?;
+    let mut deck = Deck::new(Box::new(PulseBackend::connect()?));
+    deck.load_config(&config)?;
 
-    let mut sink_controller = SinkController::create()?;
-    let mut source_controller = SourceController::create()?;
-    let bindings = make_config(&mut sink_controller, &mut source_controller)?;
-    let mut deck = Deck::new(sink_controller, source_controller, midi_out, bindings);
-    deck.clear()?;
+    // Dropping the input connection disconnects it, so hold on to it for as long as it is used
+    let mut midi_in = connect_midi(&midi_device, &mut deck, &tx);
 
-    // TODO there should be a way to get pulse to send us events instead of polling
-    let _poll_thread = thread::spawn(move || {
-        // thread code
+    let tick_tx = tx.clone();
+    let _tick_thread = thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_secs(1));
+        tick_tx
+            .send(Msg::Tick)
+            .expect("failed to send tick message to main thread");
+    });
+
+    let watch_tx = tx.clone();
+    let watch_path = config_path.clone();
+    let _watch_thread = thread::spawn(move || {
+        let mtime = |path: &PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified = mtime(&watch_path);
         loop {
-            tx.send(Msg::SyncBoard)
-                .expect("failed to send sync message to main thread");
-            thread::sleep(time::Duration::from_millis(500))
+            thread::sleep(time::Duration::from_secs(1));
+            // Editors often replace the file on save, so it can briefly be missing
+            let modified = mtime(&watch_path);
+            if modified.is_some() && modified != last_modified {
+                last_modified = modified;
+                watch_tx
+                    .send(Msg::ReloadConfig)
+                    .expect("failed to send reload message to main thread");
+            }
         }
     });
 
+    let pulse_tx = tx.clone();
+    let _pulse_thread = thread::spawn(move || {
+        events::listen(move |event| {
+            pulse_tx
+                .send(Msg::PulseUpdate(event))
+                .expect("failed to send pulse event to main thread");
+        });
+    });
+
+    // A message that was received while collecting pulse events, to be handled next
+    let mut pending = None;
     loop {
-        let msg = rx.recv();
+        let msg = match pending.take() {
+            Some(msg) => Ok(msg),
+            None => rx.recv(),
+        };
         match msg {
             Ok(msg) => match msg {
-                Msg::SyncBoard => {
-                    // println!("Sync from pulse");
-                    deck.flush_values_to_board()?;
+                Msg::PulseUpdate(event) => {
+                    // println!("Pulse event {:?}", event);
+                    let mut events = vec![event];
+                    while let Ok(msg) = rx.try_recv() {
+                        match msg {
+                            Msg::PulseUpdate(event) => events.push(event),
+                            msg => {
+                                pending = Some(msg);
+                                break;
+                            }
+                        }
+                    }
+                    // Sending can fail if the board was just unplugged, the next tick will notice
+                    if let Err(e) = deck.handle_pulse_events(&events) {
+                        eprintln!("{:#}", e);
+                    }
                 }
                 Msg::MidiUpdate(midi_msg) => {
                     // println!("{:?}", midi_msg);
-                    deck.handle_midi_message(&midi_msg)?;
+                    if let Err(e) = deck.handle_midi_message(&midi_msg) {
+                        eprintln!("{:#}", e);
+                    }
+                }
+                Msg::Tick => {
+                    if let Err(e) = deck.tick() {
+                        eprintln!("{:#}", e);
+                    }
+
+                    let present = midi_device.is_present();
+                    if midi_in.is_some() && !present {
+                        println!("MIDI device unplugged");
+                        midi_in = None;
+                        deck.set_midi_out(None)?;
+                    } else if midi_in.is_none() && present {
+                        midi_in = connect_midi(&midi_device, &mut deck, &tx);
+                    }
+                }
+                Msg::ReloadConfig => {
+                    match Config::load(&config_path).and_then(|config| deck.load_config(&config)) {
+                        Ok(()) => println!("Reloaded config from {}", config_path.display()),
+                        Err(e) => eprintln!("Keeping previous bindings: {:#}", e),
+                    }
                 }
             },
             Err(_) => break Err("Hung up".into()),
@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+
+/// Picks streams or devices by their properties, e.g.
+/// `Matcher::All(vec![Matcher::glob("application.process.binary", "wine*", false)?,
+/// Matcher::equals("media.role", "game")])`
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    /// The property is exactly this value
+    Equals(String, String),
+    /// The property matches the regex somewhere, unless it is anchored with `^` and `$`
+    Regex(String, Regex),
+    Not(Box<Matcher>),
+    /// Every matcher matches. An empty list matches everything.
+    All(Vec<Matcher>),
+    /// At least one matcher matches
+    Any(Vec<Matcher>),
+}
+
+impl Matcher {
+    pub fn equals(property: &str, value: &str) -> Self {
+        Matcher::Equals(property.to_owned(), value.to_owned())
+    }
+
+    pub fn regex(property: &str, pattern: &str, ignore_case: bool) -> Result<Self> {
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .with_context(|| format!("Invalid regex {:?} for {}", pattern, property))?;
+        Ok(Matcher::Regex(property.to_owned(), regex))
+    }
+
+    /// `*` matches any text and `?` any single character, the whole value has to match
+    pub fn glob(property: &str, pattern: &str, ignore_case: bool) -> Result<Self> {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        Self::regex(property, &regex, ignore_case)
+    }
+
+    /// Exact match, ignoring case
+    pub fn equals_ignore_case(property: &str, value: &str) -> Result<Self> {
+        Self::regex(property, &format!("^{}$", regex::escape(value)), true)
+    }
+
+    pub fn matches(&self, properties: &HashMap<String, String>) -> bool {
+        match self {
+            Matcher::Equals(property, value) => properties.get(property) == Some(value),
+            Matcher::Regex(property, regex) => properties
+                .get(property)
+                .is_some_and(|value| regex.is_match(value)),
+            Matcher::Not(matcher) => !matcher.matches(properties),
+            Matcher::All(matchers) => matchers.iter().all(|m| m.matches(properties)),
+            Matcher::Any(matchers) => matchers.iter().any(|m| m.matches(properties)),
+        }
+    }
+}
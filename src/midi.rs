@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Context, Result};
+use midir::{MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use regex::Regex;
+
+/// Used when neither the command line nor the config file pick a device
+pub const DEFAULT_DEVICE: &str = "X-TOUCH MINI";
+
+/// Where LED updates for the board are sent
+pub trait MidiSink {
+    fn send(&mut self, message: &[u8]) -> Result<()>;
+}
+
+impl MidiSink for MidiOutputConnection {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        Ok(MidiOutputConnection::send(self, message)?)
+    }
+}
+
+/// Remembers messages instead of sending them. Clones share the same list, so one can be handed
+/// to a `Deck` while another is used to check what was sent.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct RecordingMidiSink {
+    pub sent: std::rc::Rc<std::cell::RefCell<Vec<Vec<u8>>>>,
+}
+
+#[cfg(test)]
+impl MidiSink for RecordingMidiSink {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        self.sent.borrow_mut().push(message.to_vec());
+        Ok(())
+    }
+}
+
+pub fn list_ports() -> Result<()> {
+    let midi_in = MidiInput::new("DreamDeck list")?;
+    let midi_out = MidiOutput::new("DreamDeck list")?;
+
+    println!("Input ports:");
+    for name in port_names(&midi_in) {
+        println!("    {}", name);
+    }
+    println!("Output ports:");
+    for name in port_names(&midi_out) {
+        println!("    {}", name);
+    }
+    Ok(())
+}
+
+/// Matches port names that contain the pattern (ignoring case), or match it as a regex if it is
+/// written as `/regex/`.
+pub struct PortPattern {
+    pattern: String,
+    regex: Regex,
+}
+
+impl PortPattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(regex) => Regex::new(regex)
+                .with_context(|| format!("Invalid MIDI port regex {:?}", pattern))?,
+            None => Regex::new(&format!("(?i){}", regex::escape(pattern)))?,
+        };
+        Ok(PortPattern {
+            pattern: pattern.to_owned(),
+            regex,
+        })
+    }
+
+    pub fn find<T: MidiIO>(&self, midi: &T) -> Result<T::Port> {
+        midi.ports()
+            .into_iter()
+            .find(|port| {
+                midi.port_name(port)
+                    .map(|name| self.regex.is_match(&name))
+                    .unwrap_or(false)
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "No MIDI port matching {:?}, available ports are: {:?} (see --list-ports)",
+                    self.pattern,
+                    port_names(midi)
+                )
+            })
+    }
+}
+
+/// The controller we are talking to, found by port name so that it can be reconnected after
+/// being unplugged and plugged back in.
+pub struct Device {
+    input: PortPattern,
+    output: PortPattern,
+    /// Kept around to cheaply check which ports exist, as connecting consumes the `MidiInput`
+    scanner: MidiInput,
+}
+
+impl Device {
+    pub fn new(input: PortPattern, output: PortPattern) -> Result<Self> {
+        Ok(Device {
+            input,
+            output,
+            scanner: MidiInput::new("DreamDeck scan")?,
+        })
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.input.find(&self.scanner).is_ok()
+    }
+
+    pub fn connect<F>(&self, callback: F) -> Result<(MidiInputConnection<()>, MidiOutputConnection)>
+    where
+        F: FnMut(u64, &[u8], &mut ()) + Send + 'static,
+    {
+        let midi_out = MidiOutput::new("DreamDeck out")?;
+        let port = self.output.find(&midi_out)?;
+        let midi_out = midi_out
+            .connect(&port, "DreamDeck write")
+            .map_err(|e| anyhow!("Failed to connect to MIDI output: {}", e))?;
+
+        let midi_in = MidiInput::new("DreamDeak in")?;
+        // midi_in.ignore(Ignore::None);
+        let port = self.input.find(&midi_in)?;
+        let midi_in = midi_in
+            .connect(&port, "DreamDeck read", callback, ())
+            .map_err(|e| anyhow!("Failed to connect to MIDI input: {}", e))?;
+
+        Ok((midi_in, midi_out))
+    }
+}
+
+fn port_names<T: MidiIO>(midi: &T) -> Vec<String> {
+    midi.ports()
+        .iter()
+        .filter_map(|port| midi.port_name(port).ok())
+        .collect()
+}
@@ -0,0 +1,187 @@
+use serde::Deserialize;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+pub const DEFAULT_PROFILE: &str = "x-touch-mini-b";
+pub const BUILTIN_PROFILES: &[&str] = &["x-touch-mini-a", "x-touch-mini-b", "generic"];
+
+/// A physical control, identified by the CC or note number it sends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Control {
+    Knob(u8),
+    /// Not motorized, so faders have no feedback
+    Fader(u8),
+    Button(u8),
+}
+
+/// Something the user did on the surface
+#[derive(Clone, Copy, Debug)]
+pub enum Input {
+    Knob(u8, u8),
+    Fader(u8, u8),
+    /// Buttons act when released, so this only tells which layer the board is on
+    ButtonDown,
+    ButtonUp(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Note,
+    ControlChange,
+}
+
+/// Describes a MIDI control surface: which controls it has, the messages they send and how
+/// their LEDs are driven.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    /// 0 based, so what manuals call channel 11 is 10 here
+    pub channel: u8,
+    /// Knobs and faders send CCs with their absolute position
+    pub knobs: Vec<u8>,
+    pub faders: Vec<u8>,
+    pub buttons: Vec<u8>,
+    /// Buttons either send note on/off, or a CC that is non-zero while held. LEDs are set with
+    /// the same kind of message.
+    pub buttons_send: MessageKind,
+    /// Whether knobs have LED rings that follow the CC values sent to them
+    pub knob_leds: bool,
+    pub button_leds: bool,
+    /// Values that turn a button LED on and off
+    pub led_on: u8,
+    pub led_off: u8,
+}
+
+impl Profile {
+    pub fn builtin(name: &str) -> Option<Profile> {
+        // The X-Touch Mini in standard mode, with the global channel left at its default of 11
+        let x_touch_mini = Profile {
+            name: name.to_owned(),
+            channel: 10,
+            knobs: Vec::new(),
+            faders: Vec::new(),
+            buttons: Vec::new(),
+            buttons_send: MessageKind::Note,
+            knob_leds: true,
+            button_leds: true,
+            led_on: 1,
+            led_off: 0,
+        };
+
+        match name {
+            // (1) (2) ... (8)  Knob Turn
+            // [0] [1] ... [7]  Knob Press
+            // [8] [9] ... [15] Buttons
+            // [16][17]... [23]
+            // (9) Fader
+            "x-touch-mini-a" => Some(Profile {
+                knobs: (1..=8).collect(),
+                faders: vec![9],
+                buttons: (0..=23).collect(),
+                ..x_touch_mini
+            }),
+            // (11) (12) ... (18)  Knob Turn
+            // [24] [25] ... [31]  Knob Press
+            // [32] [33] ... [39]  Buttons
+            // [40] [41] ... [47]
+            // (10) Fader
+            "x-touch-mini-b" => Some(Profile {
+                knobs: (11..=18).collect(),
+                faders: vec![10],
+                buttons: (24..=47).collect(),
+                ..x_touch_mini
+            }),
+            // Every CC is a knob and every note a button
+            "generic" => Some(Profile {
+                name: name.to_owned(),
+                channel: 0,
+                knobs: (0..=127).collect(),
+                faders: Vec::new(),
+                buttons: (0..=127).collect(),
+                buttons_send: MessageKind::Note,
+                knob_leds: true,
+                button_leds: true,
+                led_on: 127,
+                led_off: 0,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn has_control(&self, control: Control) -> bool {
+        match control {
+            Control::Knob(n) => self.knobs.contains(&n),
+            Control::Fader(n) => self.faders.contains(&n),
+            Control::Button(n) => self.buttons.contains(&n),
+        }
+    }
+
+    pub fn decode(&self, message: &[u8]) -> Option<Input> {
+        let (&status, data) = message.split_first()?;
+        if status & 0x0F != self.channel {
+            return None;
+        }
+        match (status & 0xF0, data, self.buttons_send) {
+            (CONTROL_CHANGE, &[cc, value], _) if self.knobs.contains(&cc) => {
+                Some(Input::Knob(cc, value))
+            }
+            (CONTROL_CHANGE, &[cc, value], _) if self.faders.contains(&cc) => {
+                Some(Input::Fader(cc, value))
+            }
+            (CONTROL_CHANGE, &[cc, value], MessageKind::ControlChange)
+                if self.buttons.contains(&cc) =>
+            {
+                Some(if value > 0 {
+                    Input::ButtonDown
+                } else {
+                    Input::ButtonUp(cc)
+                })
+            }
+            (NOTE_ON, &[note, velocity], MessageKind::Note) if self.buttons.contains(&note) => {
+                Some(if velocity > 0 {
+                    Input::ButtonDown
+                } else {
+                    Input::ButtonUp(note)
+                })
+            }
+            (NOTE_OFF, &[note, _], MessageKind::Note) if self.buttons.contains(&note) => {
+                Some(Input::ButtonUp(note))
+            }
+            _ => None,
+        }
+    }
+
+    /// Moves the LED ring of a knob, if it has one
+    pub fn knob_led(&self, knob: u8, value: u8) -> Option<[u8; 3]> {
+        if self.knob_leds {
+            Some([CONTROL_CHANGE | self.channel, knob, value])
+        } else {
+            None
+        }
+    }
+
+    pub fn button_led(&self, button: u8, lit: bool) -> Option<[u8; 3]> {
+        let value = if lit { self.led_on } else { self.led_off };
+        self.button_led_value(button, value)
+    }
+
+    /// Sets a button's LED to any value, for boards where it picks a color or blink mode
+    pub fn button_led_value(&self, button: u8, value: u8) -> Option<[u8; 3]> {
+        match (self.button_leds, self.buttons_send) {
+            (false, _) => None,
+            (true, MessageKind::Note) => Some([NOTE_ON | self.channel, button, value]),
+            (true, MessageKind::ControlChange) => {
+                Some([CONTROL_CHANGE | self.channel, button, value])
+            }
+        }
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::builtin(DEFAULT_PROFILE).unwrap()
+    }
+}
@@ -0,0 +1,292 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use anyhow::{anyhow, bail, Result};
+use pulse::callbacks::ListResult;
+use pulse::context::{
+    introspect::{CardInfo, Introspector},
+    State,
+};
+use pulse::operation::Operation;
+use pulse::proplist::Proplist;
+use pulse::volume::ChannelVolumes;
+use pulsectl::controllers::{
+    types::{ApplicationInfo, DeviceInfo},
+    AppControl, DeviceControl, SinkController, SourceController,
+};
+use pulsectl::Handler;
+
+use crate::backend::{AudioBackend, Card, Device, DeviceKind, Stream, StreamKind};
+
+pub struct PulseBackend {
+    sink: SinkController,
+    source: SourceController,
+}
+
+impl PulseBackend {
+    pub fn connect() -> Result<Self> {
+        Ok(PulseBackend {
+            sink: SinkController::create()?,
+            source: SourceController::create()?,
+        })
+    }
+}
+
+impl AudioBackend for PulseBackend {
+    fn devices(&mut self, kind: DeviceKind) -> Result<Vec<Device>> {
+        let devices = match kind {
+            DeviceKind::Sink => self.sink.list_devices()?,
+            DeviceKind::Source => self.source.list_devices()?,
+        };
+        Ok(devices.into_iter().map(device).collect())
+    }
+
+    fn default_device(&mut self, kind: DeviceKind) -> Result<Option<Device>> {
+        let default = match kind {
+            DeviceKind::Sink => self.sink.get_default_device()?,
+            DeviceKind::Source => self.source.get_default_device()?,
+        };
+        Ok(Some(device(default)))
+    }
+
+    fn set_default_device(&mut self, kind: DeviceKind, name: &str) -> Result<()> {
+        match kind {
+            DeviceKind::Sink => self.sink.set_default_device(name)?,
+            DeviceKind::Source => self.source.set_default_device(name)?,
+        };
+        Ok(())
+    }
+
+    fn set_device_volume(
+        &mut self,
+        kind: DeviceKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        match kind {
+            DeviceKind::Sink => self.sink.set_device_volume_by_index(index, volume),
+            DeviceKind::Source => self.source.set_source_volume(index, volume)?,
+        }
+        Ok(())
+    }
+
+    fn set_device_mute(&mut self, kind: DeviceKind, index: u32, mute: bool) -> Result<()> {
+        match kind {
+            DeviceKind::Sink => self.sink.set_device_mute_by_index(index, mute),
+            DeviceKind::Source => self.source.set_device_mute_by_index(index, mute),
+        }
+        Ok(())
+    }
+
+    fn set_device_port(&mut self, kind: DeviceKind, index: u32, port: &str) -> Result<()> {
+        match kind {
+            DeviceKind::Sink => self.sink.set_sink_port(index, port),
+            DeviceKind::Source => self.source.set_source_port(index, port),
+        }
+    }
+
+    fn streams(&mut self, kind: StreamKind) -> Result<Vec<Stream>> {
+        let streams = match kind {
+            StreamKind::SinkInput => self.sink.list_applications()?,
+            StreamKind::SourceOutput => self.source.list_applications()?,
+        };
+        Ok(streams.into_iter().map(stream).collect())
+    }
+
+    fn set_stream_volume(
+        &mut self,
+        kind: StreamKind,
+        index: u32,
+        volume: &ChannelVolumes,
+    ) -> Result<()> {
+        match kind {
+            StreamKind::SinkInput => self.sink.set_sink_input_volume(index, volume),
+            StreamKind::SourceOutput => self.source.set_source_output_volume(index, volume),
+        }
+    }
+
+    fn set_stream_mute(&mut self, kind: StreamKind, index: u32, mute: bool) -> Result<()> {
+        match kind {
+            StreamKind::SinkInput => self.sink.set_app_mute(index, mute)?,
+            StreamKind::SourceOutput => self.source.set_app_mute(index, mute)?,
+        };
+        Ok(())
+    }
+
+    fn move_stream(&mut self, kind: StreamKind, index: u32, device: u32) -> Result<()> {
+        let moved = match kind {
+            StreamKind::SinkInput => self.sink.move_app_by_index(index, device)?,
+            StreamKind::SourceOutput => self.source.move_app_by_index(index, device)?,
+        };
+        if !moved {
+            bail!("Failed to move {:?} {} to {}", kind, index, device);
+        }
+        Ok(())
+    }
+
+    fn cards(&mut self) -> Result<Vec<Card>> {
+        // pulsectl has no card support, so go through the introspector it wraps
+        let cards = Rc::new(RefCell::new(Vec::new()));
+        let found = cards.clone();
+        let op = self.sink.handler.introspect.get_card_info_list(
+            move |result: ListResult<&CardInfo>| {
+                if let ListResult::Item(info) = result {
+                    found.borrow_mut().push(card(info));
+                }
+            },
+        );
+        self.sink
+            .handler
+            .wait_for_operation(op)
+            .map_err(|_| anyhow!("Failed to list cards"))?;
+        Ok(cards.take())
+    }
+
+    fn set_card_profile(&mut self, index: u32, profile: &str) -> Result<()> {
+        let description = format!("set card {} to profile {}", index, profile);
+        wait_for_success(
+            &mut self.sink.handler,
+            &description,
+            |introspect, callback| {
+                introspect.set_card_profile_by_index(index, profile, Some(callback))
+            },
+        )
+    }
+
+    /// pulsectl doesn't tell us when the server goes away, so ask the context directly
+    fn is_connected(&self) -> bool {
+        self.sink.handler.context.borrow().get_state() == State::Ready
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        *self = Self::connect()?;
+        Ok(())
+    }
+}
+
+fn device(info: DeviceInfo) -> Device {
+    Device {
+        index: info.index,
+        properties: properties(&info.proplist),
+        name: info.name,
+        volume: info.volume,
+        channel_map: info.channel_map,
+        mute: info.mute,
+        ports: info.ports.into_iter().filter_map(|p| p.name).collect(),
+        active_port: info.active_port.and_then(|p| p.name),
+    }
+}
+
+fn stream(info: ApplicationInfo) -> Stream {
+    Stream {
+        index: info.index,
+        // Also set to the source for source outputs
+        device: info.sink_id,
+        properties: properties(&info.proplist),
+        volume: info.volume,
+        channel_map: info.channel_map,
+        mute: info.mute,
+    }
+}
+
+fn card(info: &CardInfo) -> Card {
+    Card {
+        index: info.index,
+        name: info.name.as_ref().map(|name| name.to_string()),
+        profiles: info
+            .profiles
+            .iter()
+            .filter_map(|p| Some(p.name.as_ref()?.to_string()))
+            .collect(),
+        active_profile: info
+            .active_profile
+            .as_ref()
+            .and_then(|p| Some(p.name.as_ref()?.to_string())),
+        properties: properties(&info.proplist),
+    }
+}
+
+fn properties(proplist: &Proplist) -> HashMap<String, String> {
+    proplist
+        .iter()
+        .filter_map(|key| proplist.get_str(&key).map(|value| (key, value)))
+        .collect()
+}
+
+/// Runs an introspect call that only reports failure through its success callback, e.g. for a
+/// mistyped port name, and waits for it to finish
+fn wait_for_success<F>(handler: &mut Handler, description: &str, call: F) -> Result<()>
+where
+    F: FnOnce(&mut Introspector, Box<dyn FnMut(bool)>) -> Operation<dyn FnMut(bool)>,
+{
+    let succeeded = Rc::new(RefCell::new(false));
+    let result = succeeded.clone();
+    let op = call(
+        &mut handler.introspect,
+        Box::new(move |success| *result.borrow_mut() = success),
+    );
+    handler
+        .wait_for_operation(op)
+        .map_err(|_| anyhow!("Failed to {}", description))?;
+    if !succeeded.take() {
+        bail!("Failed to {}", description);
+    }
+    Ok(())
+}
+
+pub trait SinkControllerExt {
+    fn set_sink_input_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()>;
+    fn set_sink_port(&mut self, index: u32, port: &str) -> Result<()>;
+}
+impl SinkControllerExt for SinkController {
+    fn set_sink_input_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()> {
+        let op = self
+            .handler
+            .introspect
+            .set_sink_input_volume(index, vol, None);
+        self.handler
+            .wait_for_operation(op)
+            .map_err(|_| anyhow!("Failed to set sink input volume"))
+    }
+
+    fn set_sink_port(&mut self, index: u32, port: &str) -> Result<()> {
+        let description = format!("set sink {} to port {}", index, port);
+        wait_for_success(&mut self.handler, &description, |introspect, callback| {
+            introspect.set_sink_port_by_index(index, port, Some(callback))
+        })
+    }
+}
+
+pub trait SourceControllerExt {
+    fn set_source_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()>;
+    fn set_source_output_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()>;
+    fn set_source_port(&mut self, index: u32, port: &str) -> Result<()>;
+}
+impl SourceControllerExt for SourceController {
+    /// Unlike `DeviceControl::set_device_volume_by_index` this reports failures
+    fn set_source_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()> {
+        let op = self
+            .handler
+            .introspect
+            .set_source_volume_by_index(index, vol, None);
+        self.handler
+            .wait_for_operation(op)
+            .map_err(|_| anyhow!("Failed to set source volume"))
+    }
+
+    fn set_source_output_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()> {
+        let op = self
+            .handler
+            .introspect
+            .set_source_output_volume(index, vol, None);
+        self.handler
+            .wait_for_operation(op)
+            .map_err(|_| anyhow!("Failed to set source output volume"))
+    }
+
+    fn set_source_port(&mut self, index: u32, port: &str) -> Result<()> {
+        let description = format!("set source {} to port {}", index, port);
+        wait_for_success(&mut self.handler, &description, |introspect, callback| {
+            introspect.set_source_port_by_index(index, port, Some(callback))
+        })
+    }
+}
@@ -1,83 +1,191 @@
-use anyhow::{anyhow, Result};
-use pulse::volume::{ChannelVolumes, Volume};
-use pulsectl::controllers::{
-    types::ApplicationInfo, AppControl, DeviceControl, SinkController, SourceController,
+use anyhow::{anyhow, bail, Result};
+use pulse::volume::Volume;
+use serde::Deserialize;
+
+use crate::{
+    backend::{AudioBackend, Card, Device, DeviceKind, Stream, StreamKind},
+    events::PulseEvent,
+    matcher::Matcher,
 };
 
 #[derive(Clone)]
 pub enum Target {
-    StaticSink(u32),
-    StaticSource(u32),
-    SinkWithProperty(&'static str, &'static str),
+    /// Looked up by name on every use, as a device's index changes whenever it is replugged
+    SinkByName(String),
+    SourceByName(String),
+    /// The first device with matching properties, e.g. `device.serial`
+    SinkByProperty(Matcher),
+    SourceByProperty(Matcher),
+    /// Every playback stream with matching properties, e.g. all of a browser's tabs
+    SinkWithProperty(Matcher),
+    /// Every recording stream with matching properties
+    SourceOutputWithProperty(Matcher),
+    CardByName(String),
+    CardByProperty(Matcher),
     Any(Vec<Target>),
     All(Vec<Target>),
 }
 
+/// How the volumes of several streams or targets are combined into the one shown on the board
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    #[default]
+    First,
+    Max,
+    Average,
+}
+
+impl Aggregate {
+    fn apply(self, volumes: Vec<Volume>) -> Option<Volume> {
+        match self {
+            Aggregate::First => volumes.first().copied(),
+            Aggregate::Max => volumes.into_iter().max_by_key(|v| v.0),
+            Aggregate::Average if volumes.is_empty() => None,
+            Aggregate::Average => {
+                let sum: u64 = volumes.iter().map(|v| v.0 as u64).sum();
+                Some(Volume((sum / volumes.len() as u64) as u32))
+            }
+        }
+    }
+}
+
+/// Which of the existing streams follow when the default sink or source changes
+#[derive(Clone, Debug)]
+pub enum MoveStreams {
+    None,
+    All,
+    Matching(Matcher),
+}
+
 impl Target {
+    /// Whether a change reported by pulse could have changed the state of this target
+    pub fn affected_by(&self, event: PulseEvent) -> bool {
+        match (self, event) {
+            // The device could have just been plugged in with a new index
+            (Target::SinkByName(_), PulseEvent::Sink)
+            | (Target::SinkByProperty(..), PulseEvent::Sink)
+            | (Target::SourceByName(_), PulseEvent::Source)
+            | (Target::SourceByProperty(..), PulseEvent::Source) => true,
+            // A sink input could have been added, removed or had its properties changed
+            (Target::SinkWithProperty(_), PulseEvent::SinkInput)
+            | (Target::SourceOutputWithProperty(_), PulseEvent::SourceOutput) => true,
+            (Target::CardByName(_), PulseEvent::Card)
+            | (Target::CardByProperty(_), PulseEvent::Card) => true,
+            (Target::Any(targets), _) | (Target::All(targets), _) => {
+                targets.iter().any(|t| t.affected_by(event))
+            }
+            _ => false,
+        }
+    }
+
+    /// The loudest channel's volume, which is what `set_volume` sets. `aggregate` combines the
+    /// volumes of targets that are several streams or devices at once.
     pub fn volume(
         &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
+        backend: &mut dyn AudioBackend,
+        aggregate: Aggregate,
     ) -> Result<Option<Volume>> {
         match self {
-            Target::StaticSink(idx) => Ok(Some(sink.get_device_by_index(*idx)?.volume.avg())),
-            Target::StaticSource(idx) => Ok(Some(source.get_device_by_index(*idx)?.volume.avg())),
-            Target::SinkWithProperty(p, v) => {
-                Ok(Self::find_app(p, v, sink)?.map(|a| a.volume.avg()))
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => {
+                let apps = self.find_apps(backend)?.map(|(_, apps)| apps);
+                let apps = apps.unwrap_or_default();
+                Ok(aggregate.apply(apps.iter().map(|a| a.volume.max()).collect()))
             }
             Target::Any(targets) => {
                 for t in targets {
-                    if let Some(v) = t.volume(sink, source)? {
+                    if let Some(v) = t.volume(backend, aggregate)? {
                         return Ok(Some(v));
                     }
                 }
                 Ok(None)
             }
             Target::All(targets) => {
-                // Just return the first one as we want them to all be synced
+                let mut volumes = Vec::new();
                 for t in targets {
-                    if let Some(v) = t.volume(sink, source)? {
-                        return Ok(Some(v));
-                    }
+                    volumes.extend(t.volume(backend, aggregate)?);
                 }
-                Ok(None)
+                Ok(aggregate.apply(volumes))
             }
+            _ => Ok(self.device(backend)?.map(|(_, d)| d.volume.max())),
         }
     }
 
+    /// Scales every channel so that the loudest is at `new_vol`, keeping the balance between them
     pub fn set_volume(
         &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
-        new_vol: f32,
+        backend: &mut dyn AudioBackend,
+        new_vol: Volume,
     ) -> Result<Option<()>> {
         match self {
-            Target::StaticSink(idx) => {
-                let mut vol = sink.get_device_by_index(*idx)?.volume;
-                vol.set(
-                    vol.len(),
-                    Volume((new_vol * (Volume::NORMAL.0 - 1) as f32) as u32),
-                );
-                sink.set_device_volume_by_index(*idx, &vol);
-                Ok(Some(()))
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => {
+                if let Some((kind, apps)) = self.find_apps(backend)? {
+                    for app in apps {
+                        let mut vol = app.volume;
+                        vol.scale(new_vol);
+                        backend.set_stream_volume(kind, app.index, &vol)?;
+                    }
+                    Ok(Some(()))
+                } else {
+                    Ok(None)
+                }
             }
-            Target::StaticSource(idx) => {
-                let mut vol = sink.get_app_by_index(*idx)?.volume;
-                vol.set(
-                    vol.len(),
-                    Volume((new_vol * (Volume::NORMAL.0 - 1) as f32) as u32),
-                );
-                sink.set_sink_input_volume(*idx, &vol)?;
-                Ok(Some(()))
+            Target::Any(targets) => {
+                for t in targets {
+                    if let Some(v) = t.set_volume(backend, new_vol)? {
+                        return Ok(Some(v));
+                    }
+                }
+                Ok(None)
+            }
+            Target::All(targets) => targets
+                .iter()
+                .map(|t| t.set_volume(backend, new_vol))
+                .collect(),
+            _ => {
+                if let Some((kind, device)) = self.device(backend)? {
+                    let mut vol = device.volume;
+                    vol.scale(new_vol);
+                    backend.set_device_volume(kind, device.index, &vol)?;
+                    Ok(Some(()))
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    /// From -1 (left) to 1 (right)
+    pub fn balance(&self, backend: &mut dyn AudioBackend) -> Result<Option<f32>> {
+        match self {
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => Ok(self
+                .find_apps(backend)?
+                .map(|(_, apps)| apps[0].volume.get_balance(&apps[0].channel_map))),
+            Target::Any(targets) | Target::All(targets) => {
+                for t in targets {
+                    if let Some(v) = t.balance(backend)? {
+                        return Ok(Some(v));
+                    }
+                }
+                Ok(None)
             }
-            Target::SinkWithProperty(p, v) => {
-                if let Some(app) = Self::find_app(p, v, sink)? {
-                    let mut vol = app.volume;
-                    vol.set(
-                        vol.len(),
-                        Volume((new_vol * (Volume::NORMAL.0 - 1) as f32) as u32),
-                    );
-                    sink.set_sink_input_volume(app.index, &vol)?;
+            _ => Ok(self
+                .device(backend)?
+                .map(|(_, d)| d.volume.get_balance(&d.channel_map))),
+        }
+    }
+
+    pub fn set_balance(&self, backend: &mut dyn AudioBackend, balance: f32) -> Result<Option<()>> {
+        match self {
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => {
+                if let Some((kind, apps)) = self.find_apps(backend)? {
+                    for app in apps {
+                        let mut vol = app.volume;
+                        // Does nothing for mono streams
+                        if vol.set_balance(&app.channel_map, balance).is_some() {
+                            backend.set_stream_volume(kind, app.index, &vol)?;
+                        }
+                    }
                     Ok(Some(()))
                 } else {
                     Ok(None)
@@ -85,7 +193,7 @@ impl Target {
             }
             Target::Any(targets) => {
                 for t in targets {
-                    if let Some(v) = t.set_volume(sink, source, new_vol)? {
+                    if let Some(v) = t.set_balance(backend, balance)? {
                         return Ok(Some(v));
                     }
                 }
@@ -93,25 +201,31 @@ impl Target {
             }
             Target::All(targets) => targets
                 .iter()
-                .map(|t| t.set_volume(sink, source, new_vol))
+                .map(|t| t.set_balance(backend, balance))
                 .collect(),
+            _ => {
+                if let Some((kind, device)) = self.device(backend)? {
+                    let mut vol = device.volume;
+                    if vol.set_balance(&device.channel_map, balance).is_some() {
+                        backend.set_device_volume(kind, device.index, &vol)?;
+                    }
+                    Ok(Some(()))
+                } else {
+                    Ok(None)
+                }
+            }
         }
     }
 
-    pub fn muted(
-        &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
-    ) -> Result<Option<bool>> {
+    /// Streams only count as muted once all of them are
+    pub fn muted(&self, backend: &mut dyn AudioBackend) -> Result<Option<bool>> {
         match self {
-            Target::StaticSink(idx) => Ok(Some(sink.get_device_by_index(*idx).unwrap().mute)),
-            Target::StaticSource(idx) => Ok(Some(source.get_device_by_index(*idx).unwrap().mute)),
-            Target::SinkWithProperty(p, v) => {
-                Ok(Self::find_app(p, v, sink).unwrap().map(|a| a.mute))
-            }
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => Ok(self
+                .find_apps(backend)?
+                .map(|(_, apps)| apps.iter().all(|a| a.mute))),
             Target::Any(targets) => {
                 for t in targets {
-                    if let Some(v) = t.muted(sink, source).unwrap() {
+                    if let Some(v) = t.muted(backend)? {
                         return Ok(Some(v));
                     }
                 }
@@ -120,44 +234,33 @@ impl Target {
             Target::All(targets) => {
                 // Just return the first one as we want them to all be synced
                 for t in targets {
-                    if let Some(v) = t.muted(sink, source)? {
+                    if let Some(v) = t.muted(backend)? {
                         return Ok(Some(v));
                     }
                 }
                 Ok(None)
             }
+            _ => Ok(self.device(backend)?.map(|(_, d)| d.mute)),
         }
     }
 
-    pub fn toggle_muted(
-        &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
-    ) -> Result<Option<bool>> {
+    pub fn toggle_muted(&self, backend: &mut dyn AudioBackend) -> Result<Option<bool>> {
         match self {
-            Target::StaticSink(idx) => {
-                let is_muted = sink.get_device_by_index(*idx)?.mute;
-                sink.set_device_mute_by_index(*idx, !is_muted);
-                Ok(Some(!is_muted))
-            }
-            Target::StaticSource(idx) => {
-                let is_muted = source.get_device_by_index(*idx)?.mute;
-                source.set_device_mute_by_index(*idx, !is_muted);
-                Ok(Some(!is_muted))
-            }
-            Target::SinkWithProperty(p, v) => {
-                if let Some(app) = Self::find_app(p, v, sink)? {
-                    let idx = app.index;
-                    let is_muted = sink.get_app_by_index(idx)?.mute;
-                    sink.set_app_mute(idx, !is_muted)?;
-                    Ok(Some(!is_muted))
+            Target::SinkWithProperty(_) | Target::SourceOutputWithProperty(_) => {
+                if let Some((kind, apps)) = self.find_apps(backend)? {
+                    // Mute them all if any are playing, so they end up in sync
+                    let mute = !apps.iter().all(|a| a.mute);
+                    for app in apps {
+                        backend.set_stream_mute(kind, app.index, mute)?;
+                    }
+                    Ok(Some(mute))
                 } else {
                     Ok(None)
                 }
             }
             Target::Any(targets) => {
                 for t in targets {
-                    if let Some(v) = t.toggle_muted(sink, source)? {
+                    if let Some(v) = t.toggle_muted(backend)? {
                         return Ok(Some(v));
                     }
                 }
@@ -165,81 +268,544 @@ impl Target {
             }
             Target::All(targets) => targets
                 .iter()
-                .map(|t| t.toggle_muted(sink, source))
+                .map(|t| t.toggle_muted(backend))
                 .reduce(|_, r| r)
                 .unwrap(),
+            _ => {
+                if let Some((kind, device)) = self.device(backend)? {
+                    backend.set_device_mute(kind, device.index, !device.mute)?;
+                    Ok(Some(!device.mute))
+                } else {
+                    Ok(None)
+                }
+            }
         }
     }
 
-    pub fn selected(
-        &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
-    ) -> Result<Option<bool>> {
-        match self {
-            Target::StaticSink(idx) => Ok(Some(sink.get_default_device()?.index == *idx)),
-            Target::StaticSource(idx) => Ok(Some(source.get_default_device()?.index == *idx)),
-            _ => Err(anyhow!(
-                "Only StaticSink/StaticSource can be used for DefaultSelect bindings"
-            )),
+    pub fn selected(&self, backend: &mut dyn AudioBackend) -> Result<Option<bool>> {
+        match self.device(backend)? {
+            Some((kind, device)) => {
+                let default = backend.default_device(kind)?;
+                Ok(Some(default.map(|d| d.index) == Some(device.index)))
+            }
+            None => Ok(None),
         }
     }
 
+    /// Makes this the default sink or source, then moves the streams picked by `move_streams`
+    /// over to it, as pulse only uses the default for new streams
     pub fn set_as_selected(
         &self,
-        sink: &mut SinkController,
-        source: &mut SourceController,
+        backend: &mut dyn AudioBackend,
+        move_streams: &MoveStreams,
     ) -> Result<Option<()>> {
-        match self {
-            Target::StaticSink(idx) => {
-                let name = sink.get_device_by_index(*idx)?.name.ok_or_else(|| {
-                    anyhow!("Device must have a name to be set as default output")
-                })?;
-                sink.set_default_device(&name)?;
-                Ok(Some(()))
+        let (kind, device) = match self.device(backend)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let name = device
+            .name
+            .ok_or_else(|| anyhow!("Device must have a name to be set as default"))?;
+        backend.set_default_device(kind, &name)?;
+
+        let matcher = match move_streams {
+            MoveStreams::None => return Ok(Some(())),
+            MoveStreams::All => None,
+            MoveStreams::Matching(matcher) => Some(matcher),
+        };
+        // Streams recording a sink's monitor, like level meters, should stay on it
+        let monitors: Vec<u32> = match kind {
+            DeviceKind::Sink => Vec::new(),
+            DeviceKind::Source => backend
+                .devices(kind)?
+                .into_iter()
+                .filter(|d| d.properties.get("device.class").map(String::as_str) == Some("monitor"))
+                .map(|d| d.index)
+                .collect(),
+        };
+        for stream in backend.streams(kind.streams())? {
+            let matches = matcher.is_none_or(|m| m.matches(&stream.properties));
+            if !matches || stream.device == device.index || monitors.contains(&stream.device) {
+                continue;
+            }
+            // Some streams can't be moved, e.g. pavucontrol's peak meters, which shouldn't keep
+            // the others from following
+            if let Err(e) = backend.move_stream(kind.streams(), stream.index, device.index) {
+                eprintln!("Not moving {:?} {}: {:#}", kind.streams(), stream.index, e);
             }
-            Target::StaticSource(idx) => {
-                let name = source
-                    .get_device_by_index(*idx)?
-                    .name
-                    .ok_or_else(|| anyhow!("Device must have a name to be set as default input"))?;
-                source.set_default_device(&name)?;
+        }
+        Ok(Some(()))
+    }
+
+    /// Makes the device after the current default in `devices` the default, skipping ones that
+    /// aren't plugged in. Returns the index of the new default.
+    pub fn cycle(
+        backend: &mut dyn AudioBackend,
+        devices: &[Target],
+        move_streams: &MoveStreams,
+    ) -> Result<Option<usize>> {
+        let start = Self::selected_index(backend, devices)?.map_or(0, |i| i + 1);
+        for i in (0..devices.len()).map(|offset| (start + offset) % devices.len()) {
+            if devices[i].set_as_selected(backend, move_streams)?.is_some() {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Index of the device in `devices` that is currently the default
+    pub fn selected_index(
+        backend: &mut dyn AudioBackend,
+        devices: &[Target],
+    ) -> Result<Option<usize>> {
+        for (i, device) in devices.iter().enumerate() {
+            if device.selected(backend)? == Some(true) {
+                return Ok(Some(i));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the first of this target's streams is on `device`
+    pub fn is_on(&self, backend: &mut dyn AudioBackend, device: &Target) -> Result<Option<bool>> {
+        let streams = match self.find_apps(backend)? {
+            Some((_, streams)) => streams,
+            None => return Ok(None),
+        };
+        Ok(device
+            .device(backend)?
+            .map(|(_, device)| streams[0].device == device.index))
+    }
+
+    /// Moves this target's streams to the device after the one they are on in `devices`, or the
+    /// first if they aren't on any of them. Devices that aren't plugged in are skipped.
+    pub fn route(&self, backend: &mut dyn AudioBackend, devices: &[Target]) -> Result<Option<()>> {
+        let (kind, streams) = match self.find_apps(backend)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let mut found = Vec::new();
+        for device in devices {
+            found.push(device.device(backend)?.map(|(_, d)| d.index));
+        }
+        let current = found.iter().position(|d| *d == Some(streams[0].device));
+        let start = current.map_or(0, |i| i + 1);
+        let next = (0..found.len())
+            .map(|offset| (start + offset) % found.len())
+            .find_map(|i| found[i]);
+
+        match next {
+            Some(device) => {
+                for stream in streams.into_iter().filter(|s| s.device != device) {
+                    backend.move_stream(kind, stream.index, device)?;
+                }
                 Ok(Some(()))
             }
-            _ => Err(anyhow!(
-                "Only StaticSink/StaticSource can be used for DefaultSelect bindings"
-            )),
+            None => Ok(None),
+        }
+    }
+
+    /// The card's active profile, `None` if it isn't plugged in
+    pub fn active_profile(&self, backend: &mut dyn AudioBackend) -> Result<Option<String>> {
+        Ok(self.card(backend)?.and_then(|c| c.active_profile))
+    }
+
+    /// Switches the card to the profile after its active one in `profiles`, or the first one
+    /// when it is on none of them. Profiles the card doesn't have are skipped.
+    pub fn switch_profile(
+        &self,
+        backend: &mut dyn AudioBackend,
+        profiles: &[String],
+    ) -> Result<Option<()>> {
+        let card = match self.card(backend)? {
+            Some(card) => card,
+            None => return Ok(None),
+        };
+        let profiles = offered(profiles, &card.profiles, "profiles")?;
+        let next = next_after(&profiles, card.active_profile.as_ref());
+        if card.active_profile.as_ref() != Some(next) {
+            backend.set_card_profile(card.index, next)?;
+        }
+        Ok(Some(()))
+    }
+
+    /// The sink or source's active port, `None` if it isn't plugged in
+    pub fn active_port(&self, backend: &mut dyn AudioBackend) -> Result<Option<String>> {
+        Ok(self.device(backend)?.and_then(|(_, d)| d.active_port))
+    }
+
+    /// Switches the sink or source to the port after its active one in `ports`, or the first
+    /// one when it is on none of them. Ports the device doesn't have are skipped.
+    pub fn switch_port(
+        &self,
+        backend: &mut dyn AudioBackend,
+        ports: &[String],
+    ) -> Result<Option<()>> {
+        let (kind, device) = match self.device(backend)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let ports = offered(ports, &device.ports, "ports")?;
+        let next = next_after(&ports, device.active_port.as_ref());
+        if device.active_port.as_ref() != Some(next) {
+            backend.set_device_port(kind, device.index, next)?;
+        }
+        Ok(Some(()))
+    }
+
+    pub fn is_card(&self) -> bool {
+        matches!(self, Target::CardByName(_) | Target::CardByProperty(_))
+    }
+
+    /// Whether these are playback or recording streams, `None` for devices and groups of targets
+    pub fn stream_kind(&self) -> Option<StreamKind> {
+        match self {
+            Target::SinkWithProperty(_) => Some(StreamKind::SinkInput),
+            Target::SourceOutputWithProperty(_) => Some(StreamKind::SourceOutput),
+            _ => None,
+        }
+    }
+
+    /// Whether this is a sink or a source, `None` for streams and groups of targets
+    pub fn device_kind(&self) -> Option<DeviceKind> {
+        match self {
+            Target::SinkByName(_) | Target::SinkByProperty(..) => Some(DeviceKind::Sink),
+            Target::SourceByName(_) | Target::SourceByProperty(..) => Some(DeviceKind::Source),
+            _ => None,
         }
     }
 }
 
 impl Target {
-    fn find_app(
-        property: &str,
-        value: &str,
-        sink: &mut SinkController,
-    ) -> Result<Option<ApplicationInfo>> {
-        let apps = sink.list_applications()?;
-        Ok(apps.into_iter().find(|app| {
-            app.proplist
-                .get_str(property)
-                .filter(|v| value == v)
-                .is_some()
-        }))
+    /// Finds the sink or source this target currently refers to, `None` if it isn't plugged in
+    fn device(&self, backend: &mut dyn AudioBackend) -> Result<Option<(DeviceKind, Device)>> {
+        let kind = self.device_kind().ok_or_else(|| {
+            anyhow!("Only sinks and sources can be used for select, cycle and port bindings")
+        })?;
+        Ok(backend
+            .devices(kind)?
+            .into_iter()
+            .find(|d| self.is_device(d))
+            .map(|d| (kind, d)))
+    }
+
+    fn card(&self, backend: &mut dyn AudioBackend) -> Result<Option<Card>> {
+        let matches = |card: &Card| match self {
+            Target::CardByName(name) => card.name.as_ref() == Some(name),
+            Target::CardByProperty(matcher) => matcher.matches(&card.properties),
+            _ => false,
+        };
+        if !self.is_card() {
+            bail!("Only cards can be used for CardProfile bindings");
+        }
+        Ok(backend.cards()?.into_iter().find(matches))
+    }
+
+    fn is_device(&self, device: &Device) -> bool {
+        match self {
+            Target::SinkByName(name) | Target::SourceByName(name) => {
+                device.name.as_ref() == Some(name)
+            }
+            Target::SinkByProperty(matcher) | Target::SourceByProperty(matcher) => {
+                matcher.matches(&device.properties)
+            }
+            _ => false,
+        }
+    }
+
+    /// Every stream the target matches, `None` if there aren't any
+    fn find_apps(
+        &self,
+        backend: &mut dyn AudioBackend,
+    ) -> Result<Option<(StreamKind, Vec<Stream>)>> {
+        let (kind, matcher) = match self {
+            Target::SinkWithProperty(matcher) => (StreamKind::SinkInput, matcher),
+            Target::SourceOutputWithProperty(matcher) => (StreamKind::SourceOutput, matcher),
+            _ => return Ok(None),
+        };
+        let apps: Vec<Stream> = backend
+            .streams(kind)?
+            .into_iter()
+            .filter(|app| matcher.matches(&app.properties))
+            .collect();
+        Ok(if apps.is_empty() {
+            None
+        } else {
+            Some((kind, apps))
+        })
+    }
+}
+
+/// The entries of `options` that are in `available`, skipping e.g. the profiles or ports of
+/// another model of card. An error if none are, as that is most likely a typo in the config.
+fn offered(options: &[String], available: &[String], what: &str) -> Result<Vec<String>> {
+    let offered: Vec<String> = options
+        .iter()
+        .filter(|o| available.contains(o))
+        .cloned()
+        .collect();
+    if offered.is_empty() {
+        bail!(
+            "None of the {} {} exist, the available ones are: {}",
+            what,
+            options.join(", "),
+            available.join(", ")
+        );
     }
+    Ok(offered)
 }
 
-pub trait SinkControllerExt {
-    fn set_sink_input_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()>;
+/// The entry after `current` in `options`, wrapping around, or the first when `current` isn't one
+fn next_after<'a>(options: &'a [String], current: Option<&String>) -> &'a String {
+    let index = options.iter().position(|o| Some(o) == current);
+    &options[index.map_or(0, |i| (i + 1) % options.len())]
 }
-impl SinkControllerExt for SinkController {
-    fn set_sink_input_volume(&mut self, index: u32, vol: &ChannelVolumes) -> Result<()> {
-        let op = self
-            .handler
-            .introspect
-            .set_sink_input_volume(index, vol, None);
-        self.handler
-            .wait_for_operation(op)
-            .map_err(|_| anyhow!("Failed to set sink input volume"))
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::fake_backend::{device, stream, FakeBackend};
+
+    const HALF: Volume = Volume(Volume::NORMAL.0 / 2);
+
+    fn backend() -> FakeBackend {
+        let mut usb = device(2, "usb");
+        usb.properties
+            .insert("device.serial".to_owned(), "1234".to_owned());
+        let mut mic = device(3, "mic");
+        mic.properties
+            .insert("device.serial".to_owned(), "5678".to_owned());
+        FakeBackend {
+            sinks: vec![device(1, "speakers"), usb],
+            sources: vec![mic, device(4, "line")],
+            default_sink: Some(1),
+            default_source: Some(3),
+            sink_inputs: vec![
+                stream(10, 1, &[("application.name", "mpv")]),
+                stream(11, 1, &[("application.name", "firefox")]),
+            ],
+            source_outputs: vec![stream(20, 3, &[("application.name", "discord")])],
+            cards: vec![Card {
+                index: 30,
+                name: Some("headset".to_owned()),
+                profiles: vec!["a2dp_sink".to_owned(), "headset_head_unit".to_owned()],
+                active_profile: Some("a2dp_sink".to_owned()),
+                properties: HashMap::from([("device.serial".to_owned(), "9".to_owned())]),
+            }],
+            ..FakeBackend::default()
+        }
+    }
+
+    /// Indexes of the devices and streams that are no longer at 100%
+    fn changed_volumes(backend: &FakeBackend) -> Vec<u32> {
+        let devices = backend.sinks.iter().chain(&backend.sources);
+        let devices = devices.map(|d| (d.index, d.volume.max()));
+        let streams = backend.sink_inputs.iter().chain(&backend.source_outputs);
+        let streams = streams.map(|s| (s.index, s.volume.max()));
+        devices
+            .chain(streams)
+            .filter(|(_, volume)| *volume != Volume::NORMAL)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Indexes of the devices and streams that are muted
+    fn muted(backend: &FakeBackend) -> Vec<u32> {
+        let devices = backend.sinks.iter().chain(&backend.sources);
+        let devices = devices.map(|d| (d.index, d.mute));
+        let streams = backend.sink_inputs.iter().chain(&backend.source_outputs);
+        let streams = streams.map(|s| (s.index, s.mute));
+        devices
+            .chain(streams)
+            .filter(|(_, mute)| *mute)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Sets the volume of `target` to half and returns what changed
+    fn set_volume(target: Target) -> Vec<u32> {
+        let mut backend = backend();
+        assert_eq!(target.set_volume(&mut backend, HALF).unwrap(), Some(()));
+        changed_volumes(&backend)
+    }
+
+    fn toggle_muted(target: Target) -> Vec<u32> {
+        let mut backend = backend();
+        assert_eq!(target.toggle_muted(&mut backend).unwrap(), Some(true));
+        muted(&backend)
+    }
+
+    fn serial(serial: &str) -> Matcher {
+        Matcher::equals("device.serial", serial)
+    }
+
+    fn app(name: &str) -> Matcher {
+        Matcher::equals("application.name", name)
+    }
+
+    #[test]
+    fn sink_by_name() {
+        assert_eq!(set_volume(Target::SinkByName("usb".to_owned())), vec![2]);
+        assert_eq!(toggle_muted(Target::SinkByName("usb".to_owned())), vec![2]);
+    }
+
+    #[test]
+    fn source_by_name() {
+        assert_eq!(set_volume(Target::SourceByName("mic".to_owned())), vec![3]);
+        assert_eq!(
+            toggle_muted(Target::SourceByName("mic".to_owned())),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn sink_by_property() {
+        assert_eq!(set_volume(Target::SinkByProperty(serial("1234"))), vec![2]);
+        assert_eq!(
+            toggle_muted(Target::SinkByProperty(serial("1234"))),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn source_by_property() {
+        assert_eq!(
+            set_volume(Target::SourceByProperty(serial("5678"))),
+            vec![3]
+        );
+        assert_eq!(
+            toggle_muted(Target::SourceByProperty(serial("5678"))),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn sink_with_property() {
+        assert_eq!(set_volume(Target::SinkWithProperty(app("mpv"))), vec![10]);
+        assert_eq!(toggle_muted(Target::SinkWithProperty(app("mpv"))), vec![10]);
+    }
+
+    #[test]
+    fn source_output_with_property() {
+        let target = || Target::SourceOutputWithProperty(app("discord"));
+        assert_eq!(set_volume(target()), vec![20]);
+        assert_eq!(toggle_muted(target()), vec![20]);
+    }
+
+    #[test]
+    fn any_uses_the_first_that_exists() {
+        let target = || {
+            Target::Any(vec![
+                Target::SinkByName("missing".to_owned()),
+                Target::SourceByName("line".to_owned()),
+                Target::SinkByName("speakers".to_owned()),
+            ])
+        };
+        assert_eq!(set_volume(target()), vec![4]);
+        assert_eq!(toggle_muted(target()), vec![4]);
+    }
+
+    #[test]
+    fn all_uses_every_target() {
+        let target = || {
+            Target::All(vec![
+                Target::SinkByName("speakers".to_owned()),
+                Target::SourceOutputWithProperty(app("discord")),
+            ])
+        };
+        assert_eq!(set_volume(target()), vec![1, 20]);
+        assert_eq!(toggle_muted(target()), vec![1, 20]);
+    }
+
+    #[test]
+    fn missing_targets_change_nothing() {
+        let mut backend = backend();
+        let sink = Target::SinkByName("missing".to_owned());
+        assert_eq!(sink.set_volume(&mut backend, HALF).unwrap(), None);
+        let streams = Target::SinkWithProperty(app("missing"));
+        assert_eq!(streams.toggle_muted(&mut backend).unwrap(), None);
+        assert_eq!(changed_volumes(&backend), Vec::<u32>::new());
+        assert_eq!(muted(&backend), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn card_by_name_and_property() {
+        let profiles = ["a2dp_sink".to_owned(), "headset_head_unit".to_owned()];
+        for card in [
+            Target::CardByName("headset".to_owned()),
+            Target::CardByProperty(serial("9")),
+        ] {
+            let mut backend = backend();
+            card.switch_profile(&mut backend, &profiles).unwrap();
+            assert_eq!(
+                backend.cards[0].active_profile.as_deref(),
+                Some("headset_head_unit")
+            );
+            card.switch_profile(&mut backend, &profiles).unwrap();
+            assert_eq!(
+                backend.cards[0].active_profile.as_deref(),
+                Some("a2dp_sink")
+            );
+            assert!(card.set_volume(&mut backend, HALF).is_err());
+        }
+    }
+
+    #[test]
+    fn switch_profile_skips_profiles_the_card_lacks() {
+        let mut backend = backend();
+        let card = Target::CardByName("headset".to_owned());
+        let profiles = ["off".to_owned(), "headset_head_unit".to_owned()];
+        card.switch_profile(&mut backend, &profiles).unwrap();
+        assert_eq!(
+            backend.cards[0].active_profile.as_deref(),
+            Some("headset_head_unit")
+        );
+        card.switch_profile(&mut backend, &profiles).unwrap();
+        assert_eq!(
+            backend.cards[0].active_profile.as_deref(),
+            Some("headset_head_unit")
+        );
+        let typo = ["a2dp-sink".to_owned()];
+        assert!(card.switch_profile(&mut backend, &typo).is_err());
+        assert_eq!(
+            backend.cards[0].active_profile.as_deref(),
+            Some("headset_head_unit")
+        );
+    }
+
+    #[test]
+    fn switch_port_skips_ports_the_device_lacks() {
+        let mut backend = backend();
+        backend.sinks[0].ports = vec!["lineout".to_owned(), "headphones".to_owned()];
+        backend.sinks[0].active_port = Some("lineout".to_owned());
+        let sink = Target::SinkByName("speakers".to_owned());
+        let active_port = |backend: &mut FakeBackend| sink.active_port(backend).unwrap();
+        let ports = [
+            "hdmi".to_owned(),
+            "headphones".to_owned(),
+            "lineout".to_owned(),
+        ];
+        assert_eq!(active_port(&mut backend).as_deref(), Some("lineout"));
+        assert_eq!(sink.switch_port(&mut backend, &ports).unwrap(), Some(()));
+        assert_eq!(active_port(&mut backend).as_deref(), Some("headphones"));
+        assert_eq!(sink.switch_port(&mut backend, &ports).unwrap(), Some(()));
+        assert_eq!(active_port(&mut backend).as_deref(), Some("lineout"));
+        assert!(sink
+            .switch_port(&mut backend, &["line-out".to_owned()])
+            .is_err());
+        assert_eq!(active_port(&mut backend).as_deref(), Some("lineout"));
+    }
+
+    #[test]
+    fn switch_port_of_missing_or_wrong_target() {
+        let mut backend = backend();
+        let ports = ["lineout".to_owned()];
+        let missing = Target::SinkByName("missing".to_owned());
+        assert_eq!(missing.switch_port(&mut backend, &ports).unwrap(), None);
+        assert_eq!(missing.active_port(&mut backend).unwrap(), None);
+        let streams = Target::SinkWithProperty(app("mpv"));
+        assert!(streams.switch_port(&mut backend, &ports).is_err());
     }
 }